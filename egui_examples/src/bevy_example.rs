@@ -1,7 +1,7 @@
 use bevy::prelude::*;
 use bevy_egui::{egui, EguiContexts, EguiPlugin};
 use egui_graphs::{DefaultEdgeShape, DefaultNodeShape, Graph, GraphView};
-use petgraph::stable_graph::{DefaultIx, StableGraph};
+use petgraph::stable_graph::StableGraph;
 
 
 fn main() {
@@ -0,0 +1,194 @@
+use serde::{de, Deserialize, Deserializer};
+use std::fmt;
+
+/// Chords by name, as semitones above their root.
+const CHORDS: &[(&str, &[u8])] = &[
+    ("major", &[0, 4, 7]),
+    ("minor", &[0, 3, 7]),
+    ("diminished", &[0, 3, 6]),
+    ("augmented", &[0, 4, 8]),
+    ("sus2", &[0, 2, 7]),
+    ("sus4", &[0, 5, 7]),
+    ("power", &[0, 7, 12]),
+    ("major6", &[0, 4, 7, 9]),
+    ("minor6", &[0, 3, 7, 9]),
+    ("major7", &[0, 4, 7, 11]),
+    ("minor7", &[0, 3, 7, 10]),
+    ("dominant7", &[0, 4, 7, 10]),
+    ("half_diminished7", &[0, 3, 6, 10]),
+    ("diminished7", &[0, 3, 6, 9]),
+    ("minor_major7", &[0, 3, 7, 11]),
+    ("add9", &[0, 4, 7, 14]),
+    ("major9", &[0, 4, 7, 11, 14]),
+    ("minor9", &[0, 3, 7, 10, 14]),
+    ("dominant9", &[0, 4, 7, 10, 14]),
+];
+
+/// The highest inversion: with a triad, the root position an octave up.
+const MAX_INVERSION: u8 = 3;
+/// The most octaves a spread voicing moves every other note by.
+const MAX_SPREAD: u8 = 2;
+
+/// A chord by name (`"minor7"`, `"sus4"`, ...) or as a list of semitones
+/// above its root, starting at 0: `[0, 7, 14, 16]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chord {
+    pub name: String,
+    intervals: Vec<u8>,
+}
+
+impl Chord {
+    /// The chord's notes on `root`, voiced: the inversion moves its lowest notes
+    /// up an octave each, the spread then moves every other note up by as many
+    /// octaves. Notes outside the MIDI range are left out.
+    pub fn notes(&self, root: u8, voicing: Voicing) -> Vec<u8> {
+        let root = i32::from(root);
+        let mut notes: Vec<i32> = self.intervals.iter().map(|&interval| root + i32::from(interval)).collect();
+        for _ in 0..voicing.inversion {
+            if let Some(lowest) = notes.iter_mut().min() {
+                *lowest += 12;
+            }
+        }
+        notes.sort_unstable();
+        for note in notes.iter_mut().skip(1).step_by(2) {
+            *note += 12 * i32::from(voicing.spread);
+        }
+        let mut notes: Vec<u8> =
+            notes.into_iter().filter_map(|note| u8::try_from(note).ok()).filter(|&note| note < 128).collect();
+        notes.sort_unstable();
+        notes.dedup();
+        notes
+    }
+}
+
+impl<'de> Deserialize<'de> for Chord {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Name(String),
+            Intervals(Vec<u8>),
+        }
+
+        match Raw::deserialize(deserializer)? {
+            Raw::Name(name) => match CHORDS.iter().find(|(known, _)| *known == name) {
+                Some((_, intervals)) => Ok(Self {
+                    name,
+                    intervals: intervals.to_vec(),
+                }),
+                None => Err(de::Error::custom(InvalidChord(format!("unknown chord {name}")))),
+            },
+            Raw::Intervals(intervals) => {
+                let ascending = intervals.windows(2).all(|pair| pair[0] < pair[1]);
+                if intervals.first() != Some(&0) || !ascending || intervals.last().is_some_and(|&last| last > 36) {
+                    let reason = format!("chord {intervals:?} must rise from 0 to at most 36 semitones");
+                    return Err(de::Error::custom(InvalidChord(reason)));
+                }
+                Ok(Self {
+                    name: "custom".to_string(),
+                    intervals,
+                })
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidChord(String);
+
+impl fmt::Display for InvalidChord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for InvalidChord {}
+
+/// How chord buttons voice their chords, shared by all of them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Voicing {
+    pub inversion: u8,
+    pub spread: u8,
+}
+
+/// What a `voicing` mapping changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VoicingControl {
+    /// Root position to third inversion.
+    Inversion,
+    /// Close voicing to every other note two octaves up.
+    Spread,
+}
+
+impl VoicingControl {
+    pub const fn max(self) -> u8 {
+        match self {
+            Self::Inversion => MAX_INVERSION,
+            Self::Spread => MAX_SPREAD,
+        }
+    }
+}
+
+impl Voicing {
+    pub const fn get_mut(&mut self, control: VoicingControl) -> &mut u8 {
+        match control {
+            VoicingControl::Inversion => &mut self.inversion,
+            VoicingControl::Spread => &mut self.spread,
+        }
+    }
+}
+
+impl fmt::Display for Voicing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "inversion {}, spread {}", self.inversion, self.spread)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct Config {
+        chord: Chord,
+    }
+
+    fn chord(text: &str) -> Result<Chord, toml::de::Error> {
+        toml::from_str::<Config>(&format!("chord = {text}")).map(|config| config.chord)
+    }
+
+    fn voiced(name: &str, root: u8, inversion: u8, spread: u8) -> Vec<u8> {
+        let chord = chord(&format!("\"{name}\"")).expect("known chord");
+        chord.notes(root, Voicing { inversion, spread })
+    }
+
+    #[test]
+    fn inversions_move_the_lowest_note_up() {
+        assert_eq!(voiced("major", 60, 0, 0), [60, 64, 67]);
+        assert_eq!(voiced("major", 60, 1, 0), [64, 67, 72]);
+        assert_eq!(voiced("major", 60, 2, 0), [67, 72, 76]);
+        assert_eq!(voiced("major", 60, MAX_INVERSION, 0), [72, 76, 79]);
+    }
+
+    #[test]
+    fn spread_moves_every_other_note_up() {
+        assert_eq!(voiced("dominant7", 60, 0, 1), [60, 67, 76, 82]);
+        assert_eq!(voiced("dominant7", 60, 0, MAX_SPREAD), [60, 67, 88, 94]);
+        assert_eq!(voiced("dominant7", 60, 1, 1), [64, 70, 79, 84]);
+    }
+
+    #[test]
+    fn notes_out_of_range_or_doubled_are_left_out() {
+        assert_eq!(voiced("major", 120, 1, 0), [124, 127]);
+        assert_eq!(voiced("power", 60, 1, 0), [67, 72]);
+    }
+
+    #[test]
+    fn custom_intervals_must_rise_from_0_within_three_octaves() {
+        assert_eq!(chord("[0, 7, 36]").map(|chord| chord.notes(48, Voicing::default())).ok(), Some(vec![48, 55, 84]));
+        for text in ["[0, 7, 37]", "[4, 7]", "[0, 7, 7]", "[0, 7, 4]", "[]", "\"major13\""] {
+            assert!(chord(text).is_err(), "{text}");
+        }
+    }
+}
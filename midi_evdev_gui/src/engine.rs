@@ -0,0 +1,2099 @@
+use crate::chord::{Chord, Voicing, VoicingControl};
+use crate::input::{DeviceEvent, Disconnected};
+use crate::macros::MacroConfig;
+use crate::midi_utils::MidiCC;
+use crate::mackie::{self, MackieState};
+use crate::midi_in::MidiInEvent;
+use crate::mpe::{self, MpeZone};
+use crate::notes::{Holder, NoteTracker, SoundingNote};
+use crate::osc::OscOutput;
+use crate::osc_in::{self, OscInEvent};
+use crate::output::{Destination, Output};
+use crate::sink::{HexDumpSink, MidiSink};
+use crate::script::{ScriptOutput, Scripts};
+use crate::smf::{self, Recorder};
+use crate::sysex;
+use crate::timer::TimerQueue;
+use crate::transport::{self, Clock, Realtime};
+use crate::ump::{UmpOutput, Voice};
+use crate::virtual_axis::Combine;
+use crate::rtp_midi::RtpMidiSession;
+use crate::scale::{Key, Pitch};
+use crate::profile::{
+    Action, ActionConfig, ClockConfig, DeviceConfig, InputConfig, MappingConfig, MpeExpression, MpeScope, OscArg,
+    OscOutputConfig, OutputConfig, Profile, Source, Target, TempoRange, VirtualAxisConfig,
+};
+use color_eyre::eyre::{eyre, Result};
+use evdev_rs::enums::{EventCode, EventType, EV_ABS, EV_SYN};
+use evdev_rs::{InputEvent, TimeVal};
+use midi_convert::render_slice::MidiRenderSlice;
+use midi_types::{Channel, MidiMessage, Note, Value7};
+use midi_types::status::{CONTROL_CHANGE, NOTE_OFF, NOTE_ON};
+use rosc::{OscBundle, OscMessage, OscPacket, OscTime, OscType};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const MAX_JOYSTICK_VALUE: f32 = 65535.0;
+const MIDI_MAX_VALUE: u8 = 127;
+/// Replaced in OSC target addresses by the device name and the event code.
+const DEVICE_PLACEHOLDER: &str = "{device}";
+const CODE_PLACEHOLDER: &str = "{code}";
+const DEFAULT_OUTPUT: &str = "default";
+const DEFAULT_RECORDING: &str = "recording.mid";
+/// How often offline outputs are looked for.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// The longest the main loop waits for events before calling `poll`.
+const MAX_WAIT: Duration = Duration::from_millis(100);
+/// The code of the events virtual axes hand to their mappings, which match them
+/// by axis rather than by code.
+const VIRTUAL_CODE: EventCode = EventCode::EV_ABS(EV_ABS::ABS_MISC);
+
+/// Everything the engine reacts to, in the order it arrived.
+#[derive(Debug)]
+pub enum Event {
+    Device(DeviceEvent),
+    Disconnected(Disconnected),
+    MidiIn(MidiInEvent),
+    /// A command from the OSC server.
+    OscIn(OscInEvent),
+    /// SIGINT or SIGTERM: release everything and exit.
+    Shutdown,
+    /// SIGHUP: load the profile again.
+    Reload,
+}
+
+impl From<Disconnected> for Event {
+    fn from(event: Disconnected) -> Self {
+        Self::Disconnected(event)
+    }
+}
+
+impl From<DeviceEvent> for Event {
+    fn from(event: DeviceEvent) -> Self {
+        Self::Device(event)
+    }
+}
+
+impl From<MidiInEvent> for Event {
+    fn from(event: MidiInEvent) -> Self {
+        Self::MidiIn(event)
+    }
+}
+
+impl From<OscInEvent> for Event {
+    fn from(event: OscInEvent) -> Self {
+        Self::OscIn(event)
+    }
+}
+
+/// Turns device events into MIDI messages and routes them to the profile's outputs.
+pub struct Engine {
+    mappings: Vec<Mapping>,
+    outputs: Vec<Box<dyn MidiSink>>,
+    output_names: Vec<String>,
+    setup: Setup,
+    /// Output used by each device's mappings when they do not name any.
+    device_outputs: Vec<usize>,
+    /// What `{device}` stands for in OSC addresses: the device's name, or its index.
+    device_names: Vec<String>,
+    osc: Vec<OscOutput>,
+    /// The MPE zone of each output that is one.
+    mpe: Vec<Option<MpeZone>>,
+    /// What the DAW has told each output that is a Mackie Control surface.
+    mackie: Vec<Option<MackieState>>,
+    /// Last known value of each (channel, controller), per output. Our own
+    /// messages and DAW feedback both update it.
+    controllers: Vec<BTreeMap<(u8, u8), u8>>,
+    /// Controllers the DAW moved away from the axis driving them, keyed by
+    /// (output, channel, controller), with the axis value last seen while detached.
+    detached: BTreeMap<(usize, u8, u8), Option<u8>>,
+    notes: NoteTracker,
+    inputs: Vec<InputRoute>,
+    active_layer: Option<String>,
+    last_poll: Instant,
+    recorder: Option<Recorder>,
+    /// Where `toggle_recording` saves to, next to any earlier takes.
+    recording_path: PathBuf,
+    clock: Option<Clock>,
+    timers: TimerQueue<Timer>,
+    scripts: Scripts,
+    /// Last value of every code each device has reported, for scripts and
+    /// virtual axes.
+    device_state: Vec<BTreeMap<String, i32>>,
+    virtual_axes: Vec<VirtualAxis>,
+    /// Codes each device has reported since its last `SYN_REPORT`.
+    frame_changes: Vec<BTreeSet<String>>,
+    macros: Vec<MacroConfig>,
+    /// The key `degree` mappings play in, as transposed so far.
+    key: Option<Key>,
+    voicing: Voicing,
+    /// Chords whose buttons are held down, to re-voice and release.
+    chords: Vec<HeldChord>,
+    /// The macros still running, by index into `macros`.
+    running_macros: BTreeMap<usize, MacroRun>,
+}
+
+/// Something the engine does at a given time rather than in response to an event.
+#[derive(Debug)]
+enum Timer {
+    ClockTick,
+    /// Carry on with a running macro from this step.
+    MacroStep { index: usize, step: usize },
+}
+
+/// Where a running macro sends, and the notes it has left sounding so that
+/// cancelling it ends them.
+struct MacroRun {
+    outputs: Vec<usize>,
+    channel: u8,
+    notes: BTreeSet<SoundingNote>,
+}
+
+/// A `MappingConfig` with its names resolved to codes and indices.
+struct Mapping {
+    device: Option<usize>,
+    layer: Option<String>,
+    trigger: Trigger,
+    target: Target,
+    channel: Channel,
+    /// Indices into `Engine::outputs`, or `Engine::osc` for OSC targets; empty
+    /// means the device's default output, or the first OSC output.
+    outputs: Vec<usize>,
+    takeover: bool,
+    /// Contents of a `syx` target, read when the profile is loaded.
+    dump: Vec<Vec<u8>>,
+    /// Index into `Engine::scripts` of a `script` target.
+    script: Option<usize>,
+    /// Index into `Engine::macros` of a `macro` target.
+    sequence: Option<usize>,
+}
+
+/// What a mapping reacts to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Trigger {
+    Code(EventCode),
+    /// Index into `Engine::virtual_axes`.
+    Virtual(usize),
+}
+
+/// A `VirtualAxisConfig` with its device resolved.
+struct VirtualAxis {
+    name: String,
+    device: Option<usize>,
+    combine: Combine,
+    /// Last value handed to the mappings, per device, so frames that leave it
+    /// unchanged send nothing.
+    values: BTreeMap<usize, i32>,
+}
+
+/// A chord a button is holding. Its notes are tracked under the button like
+/// any other note; this is what re-voicing it needs.
+struct HeldChord {
+    holder: Holder,
+    outputs: Vec<usize>,
+    channel: u8,
+    root: u8,
+    shape: Chord,
+    /// The notes sounding now, as last voiced.
+    notes: Vec<u8>,
+}
+
+/// What a mapping asks for in response to one event.
+enum Effect {
+    Send {
+        outputs: Vec<usize>,
+        /// The MIDI 1.0 form; empty for messages that only exist in MIDI 2.0.
+        bytes: Vec<u8>,
+        /// The message at full resolution, for outputs that take MIDI 2.0.
+        midi2: Option<Voice>,
+        description: String,
+        takeover: bool,
+        holder: Holder,
+    },
+    Osc {
+        outputs: Vec<usize>,
+        message: OscMessage,
+    },
+    /// Shapes the notes in `scope` on MPE outputs, or `channel` on others.
+    Expression {
+        outputs: Vec<usize>,
+        device: usize,
+        scope: MpeScope,
+        expression: MpeExpression,
+        value: u32,
+        channel: u8,
+    },
+    Action(Action),
+    /// Move the key by this many semitones.
+    Transpose(i32),
+    /// Start a chord, with no notes voiced yet.
+    Chord(HeldChord),
+    Voicing(VoicingControl, VoicingChange),
+    /// Start or cancel a macro.
+    Macro {
+        index: usize,
+        outputs: Vec<usize>,
+        channel: u8,
+    },
+    /// The clock's tempo or phase changes.
+    Clock(ClockChange),
+    Script(ScriptCall),
+}
+
+/// A script function to call, with what it sends MIDI on.
+struct ScriptCall {
+    script: usize,
+    function: String,
+    input: rhai::Map,
+    outputs: Vec<usize>,
+    channel: u8,
+    takeover: bool,
+    holder: Holder,
+}
+
+#[derive(Clone, Copy)]
+enum ClockChange {
+    /// Tick right away: a Start or Continue was sent, and the first tick after it
+    /// starts the song.
+    Restart,
+    Tempo(f64),
+    /// Change the tempo by this many BPM.
+    Step(f64),
+    /// Bend the tempo by this many BPM until the next nudge.
+    Nudge(f64),
+    Tap,
+}
+
+#[derive(Clone, Copy)]
+enum VoicingChange {
+    /// One step up, from the last back to the first.
+    Next,
+    /// Up or down by this many steps, as far as they go.
+    Step(i32),
+    /// Across the whole range, 0.0-1.0.
+    Set(f64),
+}
+
+/// How one of the profile's outputs is opened.
+enum OutputKind {
+    Midi(Option<Destination>),
+    RtpMidi(u16),
+    Ump(PathBuf),
+}
+
+/// What to do with messages arriving on a profile input.
+struct InputRoute {
+    /// Output whose controller values the input reports.
+    output: usize,
+    actions: Vec<ActionConfig>,
+    /// Outputs that get a copy of everything (MIDI thru).
+    thru: Vec<usize>,
+    /// Channel each channel is forwarded on, `None` where it is filtered out.
+    channel_map: [Option<u8>; 16],
+    /// A SysEx message still waiting for its `F7`.
+    sysex: Vec<u8>,
+}
+
+/// The parts of a profile the engine is built around. Mappings resolve their
+/// device and OSC output names against these, and a reload may not change them.
+#[derive(PartialEq)]
+struct Setup {
+    devices: Vec<DeviceConfig>,
+    outputs: Vec<OutputConfig>,
+    osc_outputs: Vec<OscOutputConfig>,
+    inputs: Vec<InputConfig>,
+    clock: Option<ClockConfig>,
+}
+
+impl Setup {
+    fn of(profile: &Profile) -> Self {
+        Self {
+            devices: profile.devices.clone(),
+            outputs: profile.outputs.clone(),
+            osc_outputs: profile.osc_outputs.clone(),
+            inputs: profile.inputs.clone(),
+            clock: profile.clock.clone(),
+        }
+    }
+
+    /// The output each device's mappings use when they do not name any: its
+    /// `virtual_port`, otherwise the first.
+    fn device_outputs(&self, output_names: &[String]) -> Result<Vec<usize>> {
+        self.devices
+            .iter()
+            .map(|device| match &device.virtual_port {
+                Some(port) => output_names
+                    .iter()
+                    .position(|name| name == port)
+                    .ok_or_else(|| eyre!("No output for virtual port {port}")),
+                None if output_names.is_empty() => Err(eyre!("Profile has no outputs")),
+                None => Ok(0),
+            })
+            .collect()
+    }
+
+    /// Index of the device called `name`.
+    fn device(&self, name: &str) -> Option<usize> {
+        self.devices.iter().position(|device| device.name.as_deref() == Some(name))
+    }
+}
+
+impl InputRoute {
+    /// Resolve an input's output names against the engine's outputs.
+    fn resolve(input: &InputConfig, names: &[String]) -> Result<Self> {
+        let output = match &input.output {
+            Some(name) => output_index(names, name)?,
+            None => 0,
+        };
+        let thru = input.thru.iter().map(|name| output_index(names, name)).collect::<Result<_>>()?;
+
+        let remapped = input.remap.iter().flat_map(|(from, to)| [from, to]);
+        if let Some(channel) = input.channels.iter().chain(remapped).find(|&&channel| channel > 15) {
+            return Err(eyre!("MIDI channel {channel} out of range 0-15"));
+        }
+        let mut channel_map = [None; 16];
+        for (channel, slot) in (0..).zip(&mut channel_map) {
+            if input.channels.is_empty() || input.channels.contains(&channel) {
+                let to = input.remap.iter().find(|(from, _)| *from == channel).map_or(channel, |&(_, to)| to);
+                *slot = Some(to);
+            }
+        }
+
+        Ok(Self {
+            output,
+            actions: input.actions.clone(),
+            thru,
+            channel_map,
+            sysex: Vec::new(),
+        })
+    }
+}
+
+impl Engine {
+    /// Open the profile's outputs and resolve its mappings.
+    ///
+    /// `destination` comes from the command line and replaces the first output's.
+    /// With `dump`, no MIDI ports are opened and every output prints hex to stdout.
+    pub fn new(profile: &Profile, destination: Option<Destination>, dump: bool) -> Result<Self> {
+        let mut declared = Vec::new();
+        for output in &profile.outputs {
+            let destination = output.destination()?;
+            let kind = match (output.rtp_midi, &output.ump) {
+                (Some(port), _) => OutputKind::RtpMidi(port),
+                (None, Some(device)) => OutputKind::Ump(device.clone()),
+                (None, None) => OutputKind::Midi(destination),
+            };
+            declared.push((output.name.clone(), kind));
+        }
+        match (declared.first_mut(), destination) {
+            (Some((_, first)), Some(destination)) => *first = OutputKind::Midi(Some(destination)),
+            (None, destination) if profile.devices.iter().any(|device| device.virtual_port.is_none()) => {
+                declared.push((DEFAULT_OUTPUT.to_string(), OutputKind::Midi(destination)));
+            }
+            (None, Some(_)) => return Err(eyre!("--port given but the profile has nowhere to use it")),
+            (_, None) => {}
+        }
+        // Devices with a virtual port of their own get a dedicated output that
+        // mappings can also route to by the port's name.
+        for device in &profile.devices {
+            if let Some(port) = &device.virtual_port {
+                declared.push((port.clone(), OutputKind::Midi(Some(Destination::Virtual(port.clone())))));
+            }
+        }
+
+        let mut outputs: Vec<(String, Box<dyn MidiSink>)> = Vec::new();
+        for (name, kind) in declared {
+            let sink: Box<dyn MidiSink> = match kind {
+                OutputKind::Ump(_) if dump => Box::new(HexDumpSink::ump(&name)),
+                _ if dump => Box::new(HexDumpSink::new(&name)),
+                OutputKind::Midi(destination) => Box::new(Output::open(destination.as_ref())?),
+                OutputKind::RtpMidi(port) => Box::new(RtpMidiSession::open(&name, port)?),
+                OutputKind::Ump(device) => Box::new(UmpOutput::open(&name, &device)?),
+            };
+            outputs.push((name, sink));
+        }
+        Self::with_outputs(profile, outputs)
+    }
+
+    /// Build an engine writing to already opened sinks, named as the profile refers to them.
+    ///
+    /// A device's `virtual_port` names the sink its unrouted mappings use; otherwise
+    /// that is the first sink.
+    pub fn with_outputs(profile: &Profile, outputs: Vec<(String, Box<dyn MidiSink>)>) -> Result<Self> {
+        let (names, outputs): (Vec<_>, Vec<_>) = outputs.into_iter().unzip();
+
+        let device_names = (0..)
+            .zip(&profile.devices)
+            .map(|(index, device)| device.name.clone().unwrap_or_else(|| format!("{index}")))
+            .collect();
+        let osc = profile.osc_outputs.iter().map(OscOutput::open).collect::<Result<_>>()?;
+        let mackie = names
+            .iter()
+            .map(|name| {
+                let output = profile.outputs.iter().find(|output| output.name == *name)?;
+                output.mackie.then(MackieState::default)
+            })
+            .collect();
+        let mpe = names
+            .iter()
+            .map(|name| {
+                let output = profile.outputs.iter().find(|output| output.name == *name)?;
+                output.mpe.as_ref().map(MpeZone::new)
+            })
+            .collect();
+
+        let setup = Setup::of(profile);
+        let device_outputs = setup.device_outputs(&names)?;
+        let virtual_axes = profile
+            .virtual_axes
+            .iter()
+            .map(|axis| VirtualAxis::resolve(axis, profile, &setup))
+            .collect::<Result<_>>()?;
+        for config in &profile.macros {
+            config.validate()?;
+        }
+        let mut scripts = Scripts::new();
+        let mappings = profile
+            .mappings
+            .iter()
+            .map(|mapping| Mapping::resolve(mapping, profile, &setup, &names, &mut scripts))
+            .collect::<Result<_>>()?;
+
+        let inputs = profile
+            .inputs
+            .iter()
+            .map(|input| InputRoute::resolve(input, &names))
+            .collect::<Result<_>>()?;
+
+        let clock = profile
+            .clock
+            .as_ref()
+            .map(|clock| {
+                let outputs = if clock.outputs.is_empty() {
+                    vec![0]
+                } else {
+                    clock.outputs.iter().map(|name| output_index(&names, name)).collect::<Result<_>>()?
+                };
+                Ok::<_, color_eyre::Report>(Clock::new(clock.tempo, outputs))
+            })
+            .transpose()?;
+
+        let mut engine = Self {
+            mappings,
+            controllers: vec![BTreeMap::new(); outputs.len()],
+            outputs,
+            output_names: names,
+            setup,
+            device_outputs,
+            device_names,
+            osc,
+            mpe,
+            mackie,
+            detached: BTreeMap::new(),
+            notes: NoteTracker::default(),
+            inputs,
+            active_layer: None,
+            last_poll: Instant::now(),
+            recorder: None,
+            recording_path: profile.recording.clone().unwrap_or_else(|| PathBuf::from(DEFAULT_RECORDING)),
+            clock,
+            timers: TimerQueue::default(),
+            scripts,
+            device_state: vec![BTreeMap::new(); profile.devices.len()],
+            virtual_axes,
+            frame_changes: vec![BTreeSet::new(); profile.devices.len()],
+            macros: profile.macros.clone(),
+            key: profile.key.as_ref().map(|key| Key::new(key.root, key.scale.clone())),
+            voicing: Voicing::default(),
+            chords: Vec::new(),
+            running_macros: BTreeMap::new(),
+        };
+        if let Some(clock) = &engine.clock {
+            println!("Sending MIDI clock at {:.1} BPM", clock.tempo());
+            engine.timers.schedule(Instant::now(), Timer::ClockTick);
+        }
+        for index in 0..engine.outputs.len() {
+            engine.configure_mpe(index, now());
+        }
+        Ok(engine)
+    }
+
+    pub fn handle(&mut self, event: Event) {
+        match event {
+            Event::Device(event) => self.handle_device(event),
+            Event::Disconnected(Disconnected(device)) => self.handle_disconnect(device),
+            Event::MidiIn(event) => self.handle_midi_in(&event),
+            Event::OscIn(event) => self.handle_osc_in(&event),
+            // The main loop owns the process and the profile file, so it deals with these
+            Event::Shutdown | Event::Reload => {}
+        }
+    }
+
+    fn handle_device(&mut self, DeviceEvent { device, event }: DeviceEvent) {
+        let timestamp = timestamp(&event.time);
+
+        // The end of a device's frame completes its virtual axes, then sends the
+        // OSC bundles it filled
+        if event.event_code == EventCode::EV_SYN(EV_SYN::SYN_REPORT) {
+            self.update_virtual_axes(device, &event);
+            for osc in &mut self.osc {
+                osc.flush();
+            }
+            return;
+        }
+
+        if let Some(state) = self.device_state.get_mut(device) {
+            state.insert(event.event_code.to_string(), event.value);
+        }
+        if let Some(changes) = self.frame_changes.get_mut(device) {
+            changes.insert(event.event_code.to_string());
+        }
+
+        // A released key ends whatever notes it started, even if its mapping
+        // has since been switched away.
+        if event.event_type() == Some(EventType::EV_KEY) && event.value == 0 {
+            let held = self.notes.take(|holder| holder == Some(&(device, event.event_code)));
+            self.release(timestamp, held);
+            self.chords.retain(|chord| chord.holder != (device, event.event_code));
+        }
+
+        self.apply_mappings(device, Trigger::Code(event.event_code), &event);
+    }
+
+    /// Recompute the virtual axes of `device` whose components changed in the
+    /// frame `event` ends, from the frame's final values, so axes that moved
+    /// together are combined as one movement.
+    fn update_virtual_axes(&mut self, device: usize, event: &InputEvent) {
+        let Some(changes) = self.frame_changes.get_mut(device).map(std::mem::take) else {
+            return;
+        };
+        for index in 0..self.virtual_axes.len() {
+            let axis = &self.virtual_axes[index];
+            let moved = axis.combine.components().iter().any(|code| changes.contains(*code));
+            if !moved || axis.device.is_some_and(|only| only != device) {
+                continue;
+            }
+            let state = &self.device_state[device];
+            let Some(value) = axis.combine.compute(|code| state.get(code).map(|&value| normalise(code, value))) else {
+                continue;
+            };
+            let value = axis_position(value);
+            if self.virtual_axes[index].values.insert(device, value) == Some(value) {
+                continue;
+            }
+            let event = InputEvent::new(&event.time, &VIRTUAL_CODE, value);
+            self.apply_mappings(device, Trigger::Virtual(index), &event);
+        }
+    }
+
+    /// Run the mappings `trigger` fires for `device` on `event`.
+    fn apply_mappings(&mut self, device: usize, trigger: Trigger, event: &InputEvent) {
+        let timestamp = timestamp(&event.time);
+        // What the event is called in messages, OSC addresses and script input
+        let source = match trigger {
+            Trigger::Code(code) => code.to_string(),
+            Trigger::Virtual(index) => self.virtual_axes[index].name.clone(),
+        };
+
+        let mut effects = Vec::new();
+        for mapping in &self.mappings {
+            if mapping.trigger != trigger || mapping.device.is_some_and(|only| only != device) {
+                continue;
+            }
+            if mapping.layer.as_ref().is_some_and(|layer| self.active_layer.as_ref() != Some(layer)) {
+                continue;
+            }
+            self.mapping_effects(mapping, device, &source, event, &mut effects);
+        }
+        for effect in effects {
+            self.apply_effect(effect, timestamp, &source, event.value);
+        }
+    }
+
+    /// What `mapping` asks for in response to `event`, added to `effects`.
+    #[allow(clippy::too_many_lines)]
+    fn mapping_effects(
+        &self,
+        mapping: &Mapping,
+        device: usize,
+        source: &str,
+        event: &InputEvent,
+        effects: &mut Vec<Effect>,
+    ) {
+        let outputs = if mapping.outputs.is_empty() {
+            vec![self.device_outputs[device]]
+        } else {
+            mapping.outputs.clone()
+        };
+        let (messages, description) = match &mapping.target {
+            Target::Cc(control) => {
+                let voice = Voice::ControlChange {
+                    channel: mapping.channel.into(),
+                    control: *control as u8,
+                    value: axis_value(event.value),
+                };
+                let bytes = voice.to_midi1().unwrap_or_default();
+                let value = bytes.get(2).copied().unwrap_or_default();
+                let description = format!("Axis moved: {} converted to MIDI {:?}", event.value, value);
+                (vec![(bytes, Some(voice))], description)
+            }
+            // Full velocity for note on; the release above sends the note off,
+            // and key repeats (value 2) are ignored
+            Target::Note(note) if event.event_type() == Some(EventType::EV_KEY) && event.value == 1 => (
+                vec![note_on(mapping.channel, *note)],
+                format!("Button {source}: {} converted to MIDI Note {}", event.value, note),
+            ),
+            Target::Degree(degree) if event.value == 1 => {
+                let Some(key) = &self.key else {
+                    return;
+                };
+                let Some(note) = key.note(i32::from(*degree)) else {
+                    return;
+                };
+                (
+                    vec![note_on(mapping.channel, note)],
+                    format!("Button {source}: degree {degree} of {key} converted to MIDI Note {note}"),
+                )
+            }
+            Target::NotePitch(note) | Target::NoteController { note, .. } => {
+                let value = match (event.event_type(), event.value) {
+                    (Some(EventType::EV_KEY), 0) => 0,
+                    (Some(EventType::EV_KEY), 1) => u32::MAX,
+                    (Some(EventType::EV_KEY), _) => return,
+                    _ => axis_value(event.value),
+                };
+                let (channel, note) = (mapping.channel.into(), *note);
+                let voice = match mapping.target {
+                    Target::NoteController { controller, .. } => Voice::PerNoteController {
+                        channel,
+                        note,
+                        controller,
+                        value,
+                    },
+                    _ => Voice::PerNotePitchBend { channel, note, value },
+                };
+                (
+                    vec![(Vec::new(), Some(voice))],
+                    format!("{source}: {} converted to MIDI 2.0 {voice:?}", event.value),
+                )
+            }
+            Target::Mpe(target) => {
+                let value = match (event.event_type(), event.value) {
+                    (Some(EventType::EV_KEY), 0) => 0,
+                    (Some(EventType::EV_KEY), 1) => u32::MAX,
+                    (Some(EventType::EV_KEY), _) => return,
+                    _ => axis_value(event.value),
+                };
+                effects.push(Effect::Expression {
+                    outputs,
+                    device,
+                    scope: target.scope,
+                    expression: target.expression,
+                    value,
+                    channel: mapping.channel.into(),
+                });
+                return;
+            }
+            Target::MackieButton(button) => {
+                let pressed = match event.value {
+                    0 => false,
+                    1 => true,
+                    _ => return,
+                };
+                (
+                    vec![(button.message(pressed).to_vec(), None)],
+                    format!("Button {source}: {} converted to Mackie {button}", event.value),
+                )
+            }
+            Target::MackieFader(fader) => {
+                let position = u16::try_from(event.value.clamp(0, 0xFFFF) >> 2).unwrap_or_default();
+                (
+                    vec![(mackie::fader_message(*fader, position).to_vec(), None)],
+                    format!("Axis moved: {} converted to Mackie fader {fader} at {position}", event.value),
+                )
+            }
+            Target::Mmc(command) if event.value == 1 => (
+                vec![(command.message(), None)],
+                format!("Button {source}: sending MMC {command:?}"),
+            ),
+            Target::Realtime(message) if event.value == 1 => {
+                if *message != Realtime::Stop {
+                    effects.push(Effect::Clock(ClockChange::Restart));
+                }
+                (
+                    vec![(vec![message.byte()], None)],
+                    format!("Button {source}: sending {message:?}"),
+                )
+            }
+            Target::Tempo(TempoRange { min, max }) => {
+                if event.event_type() == Some(EventType::EV_KEY) {
+                    return;
+                }
+                let position = f64::from(event.value) / f64::from(MAX_JOYSTICK_VALUE);
+                effects.push(Effect::Clock(ClockChange::Tempo(min + (max - min) * position)));
+                return;
+            }
+            Target::TempoNudge(bpm) => {
+                let change = match (event.event_type(), event.value) {
+                    (Some(EventType::EV_KEY), 1) => ClockChange::Step(*bpm),
+                    (Some(EventType::EV_KEY), _) => return,
+                    _ => {
+                        let deflection = (f64::from(event.value) / f64::from(MAX_JOYSTICK_VALUE)).mul_add(2.0, -1.0);
+                        ClockChange::Nudge(bpm * deflection)
+                    }
+                };
+                effects.push(Effect::Clock(change));
+                return;
+            }
+            Target::Script(target) => {
+                let Some(script) = mapping.script else {
+                    return;
+                };
+                effects.push(Effect::Script(ScriptCall {
+                    script,
+                    function: target.function.clone(),
+                    input: self.script_input(device, source, event),
+                    outputs,
+                    channel: mapping.channel.into(),
+                    takeover: mapping.takeover,
+                    holder: (device, event.event_code),
+                }));
+                return;
+            }
+            Target::Sysex(template) => {
+                let value = match (event.event_type(), event.value) {
+                    (Some(EventType::EV_KEY), 0) => 0,
+                    (Some(EventType::EV_KEY), 1) => MIDI_MAX_VALUE,
+                    (Some(EventType::EV_KEY), _) => return,
+                    _ => map_value(event.value),
+                };
+                (
+                    vec![(template.render(value), None)],
+                    format!("{source}: {} converted to SysEx value {}", event.value, value),
+                )
+            }
+            Target::Syx(path) if event.value == 1 => (
+                mapping.dump.iter().map(|message| (message.clone(), None)).collect(),
+                format!("Button {source}: sending {}", path.display()),
+            ),
+            Target::Osc(target) => {
+                let is_key = event.event_type() == Some(EventType::EV_KEY);
+                if is_key && event.value > 1 {
+                    return;
+                }
+                let arg = match target.arg {
+                    OscArg::Float if is_key => OscType::Float(osc_float(event.value)),
+                    OscArg::Float => OscType::Float(osc_float(event.value) / MAX_JOYSTICK_VALUE),
+                    OscArg::Int => OscType::Int(event.value),
+                    OscArg::Bool => OscType::Bool(event.value != 0),
+                };
+                let addr = target
+                    .address
+                    .replace(DEVICE_PLACEHOLDER, &self.device_names[device])
+                    .replace(CODE_PLACEHOLDER, source);
+                let outputs = if mapping.outputs.is_empty() { vec![0] } else { mapping.outputs.clone() };
+                effects.push(Effect::Osc {
+                    outputs,
+                    message: OscMessage { addr, args: vec![arg] },
+                });
+                return;
+            }
+            Target::Action(action) if event.value == 1 => {
+                effects.push(Effect::Action(*action));
+                return;
+            }
+            Target::Chord(chord) if event.value == 1 => {
+                let Some(root) = self.key.as_ref().and_then(|key| key.note(i32::from(chord.degree))) else {
+                    return;
+                };
+                effects.push(Effect::Chord(HeldChord {
+                    holder: (device, event.event_code),
+                    outputs,
+                    channel: mapping.channel.into(),
+                    root,
+                    shape: chord.shape.clone(),
+                    notes: Vec::new(),
+                }));
+                return;
+            }
+            Target::Voicing(control) => {
+                let change = match (event.event_type(), event.value) {
+                    (Some(EventType::EV_KEY), 1) => VoicingChange::Next,
+                    (Some(EventType::EV_KEY), _) => return,
+                    (_, value) if source.starts_with("ABS_HAT") => match value {
+                        -1 | 1 => VoicingChange::Step(value),
+                        _ => return,
+                    },
+                    (_, value) => VoicingChange::Set(f64::from(value) / f64::from(MAX_JOYSTICK_VALUE)),
+                };
+                effects.push(Effect::Voicing(*control, change));
+                return;
+            }
+            Target::Transpose(semitones) => {
+                let direction = match event.value {
+                    1 if event.event_type() == Some(EventType::EV_KEY) => 1,
+                    value @ (-1 | 1) if source.starts_with("ABS_HAT") => value,
+                    _ => return,
+                };
+                effects.push(Effect::Transpose(i32::from(*semitones) * direction));
+                return;
+            }
+            Target::Macro(_) if event.value == 1 => {
+                if let Some(index) = mapping.sequence {
+                    effects.push(Effect::Macro {
+                        index,
+                        outputs,
+                        channel: mapping.channel.into(),
+                    });
+                }
+                return;
+            }
+            Target::Note(_)
+            | Target::Degree(_)
+            | Target::Chord(_)
+            | Target::Syx(_)
+            | Target::Mmc(_)
+            | Target::Realtime(_)
+            | Target::Action(_)
+            | Target::Macro(_) => return,
+        };
+
+        effects.extend(messages.into_iter().map(|(bytes, midi2)| Effect::Send {
+            outputs: outputs.clone(),
+            bytes,
+            midi2,
+            description: description.clone(),
+            takeover: mapping.takeover,
+            holder: (device, event.event_code),
+        }));
+    }
+
+    /// Carry out one effect of an event, which `source` and `value` describe.
+    fn apply_effect(&mut self, effect: Effect, timestamp: Duration, source: &str, value: i32) {
+        match effect {
+            Effect::Send {
+                outputs,
+                bytes,
+                midi2,
+                description,
+                takeover,
+                holder,
+            } => {
+                for index in outputs {
+                    if takeover && !self.picked_up(index, &bytes) {
+                        continue;
+                    }
+                    match (&self.mpe[index], bytes.as_slice()) {
+                        (Some(_), &[status, note, velocity]) if status & 0xF0 == NOTE_ON && velocity > 0 => {
+                            self.mpe_note_on(index, timestamp, note, velocity, holder);
+                        }
+                        _ => self.send_message(index, timestamp, &bytes, midi2.as_ref(), Some(holder)),
+                    }
+                    println!("{description} on {}", self.outputs[index].name());
+                }
+            }
+            Effect::Osc { outputs, message } => {
+                for index in outputs {
+                    let osc = &mut self.osc[index];
+                    println!("{source}: {value} sent as OSC {} on {}", message.addr, osc.name());
+                    osc.send(message.clone());
+                }
+            }
+            Effect::Expression {
+                outputs,
+                device,
+                scope,
+                expression,
+                value: level,
+                channel,
+            } => {
+                for index in outputs {
+                    let channels = self.mpe[index].as_mut().map_or_else(
+                        || vec![channel],
+                        |zone| {
+                            zone.set_expression(scope, device, expression, level);
+                            zone.channels(scope, device)
+                        },
+                    );
+                    for channel in channels {
+                        let (bytes, voice) = mpe::expression_message(expression, channel, level);
+                        self.send_message(index, timestamp, &bytes, Some(&voice), None);
+                    }
+                    let name = self.outputs[index].name();
+                    println!("{source}: {value} converted to MPE {expression:?} on {name}");
+                }
+            }
+            Effect::Script(call) => self.run_script(call, timestamp, source, value),
+            Effect::Action(Action::Panic) => self.panic(timestamp),
+            Effect::Action(Action::ToggleRecording) => self.toggle_recording(timestamp),
+            Effect::Action(Action::TapTempo) => self.change_clock(ClockChange::Tap),
+            Effect::Macro { index, outputs, channel } => self.toggle_macro(index, outputs, channel),
+            Effect::Chord(chord) => {
+                self.chords.push(chord);
+                self.voice_chord(self.chords.len() - 1, timestamp);
+            }
+            Effect::Voicing(control, change) => self.change_voicing(control, change, timestamp),
+            Effect::Transpose(semitones) => {
+                if let Some(key) = &mut self.key {
+                    key.transpose(semitones);
+                    println!("Key: {key}");
+                }
+            }
+            Effect::Clock(change) => self.change_clock(change),
+        }
+    }
+
+    /// Call a script function and send what it returns: MIDI to the call's
+    /// outputs, OSC to the output each message names.
+    fn run_script(&mut self, call: ScriptCall, timestamp: Duration, source: &str, value: i32) {
+        let results = match self.scripts.call(call.script, &call.function, call.input, call.channel) {
+            Ok(results) => results,
+            Err(e) => {
+                eprintln!("{e}");
+                return;
+            }
+        };
+        for result in results {
+            let effect = match result {
+                ScriptOutput::Midi(bytes) => Effect::Send {
+                    outputs: call.outputs.clone(),
+                    bytes,
+                    midi2: None,
+                    description: format!("{source}: {value} run through {}", call.function),
+                    takeover: call.takeover,
+                    holder: call.holder,
+                },
+                ScriptOutput::Osc(name, message) => {
+                    let index = match &name {
+                        Some(name) => self.osc.iter().position(|osc| osc.name() == name),
+                        None => (!self.osc.is_empty()).then_some(0),
+                    };
+                    let Some(index) = index else {
+                        eprintln!("Script sent OSC {} to a missing osc_output", message.addr);
+                        continue;
+                    };
+                    Effect::Osc {
+                        outputs: vec![index],
+                        message,
+                    }
+                }
+            };
+            self.apply_effect(effect, timestamp, source, value);
+        }
+    }
+
+    /// What a script function gets: the event, normalised (axes 0.0-1.0, keys 0.0
+    /// or 1.0), and the normalised state of every code its device has reported.
+    /// `source` is the event's code, or the name of the virtual axis it comes from.
+    fn script_input(&self, device: usize, source: &str, event: &InputEvent) -> rhai::Map {
+        let state: rhai::Map = self.device_state[device]
+            .iter()
+            .map(|(code, &value)| (code.as_str().into(), normalise(code, value).into()))
+            .collect();
+        rhai::Map::from([
+            ("device".into(), self.device_names[device].clone().into()),
+            ("code".into(), source.into()),
+            ("value".into(), i64::from(event.value).into()),
+            ("normalized".into(), normalise(&event.event_code.to_string(), event.value).into()),
+            ("key".into(), (event.event_type() == Some(EventType::EV_KEY)).into()),
+            ("state".into(), state.into()),
+        ])
+    }
+
+    /// End the notes a device's keys are holding, now that it is gone.
+    fn handle_disconnect(&mut self, device: usize) {
+        let held = self.notes.take(|holder| holder.is_some_and(|&(from, _)| from == device));
+        self.release(now(), held);
+        self.chords.retain(|chord| chord.holder.0 != device);
+    }
+
+    /// End every sounding note, on every output.
+    pub fn release_all(&mut self) {
+        let sounding = self.notes.take(|_| true);
+        self.release(now(), sounding);
+        self.chords.clear();
+    }
+
+    fn release(&mut self, timestamp: Duration, notes: Vec<SoundingNote>) {
+        for (index, channel, note) in notes {
+            self.send(index, timestamp, &[NOTE_OFF | channel, note, 0], None);
+        }
+    }
+
+    /// All Notes Off and Reset All Controllers on every channel of every output.
+    fn panic(&mut self, timestamp: Duration) {
+        println!("Panic: silencing every output");
+        self.cancel_macros();
+        self.notes.take(|_| true);
+        self.chords.clear();
+        for zone in self.mpe.iter_mut().flatten() {
+            zone.reset();
+        }
+        for index in 0..self.outputs.len() {
+            for channel in 0..16 {
+                for control in [MidiCC::AllNotesOff, MidiCC::ResetAllControllers] {
+                    self.send(index, timestamp, &[CONTROL_CHANGE | channel, control as u8, 0], None);
+                }
+            }
+        }
+    }
+
+    /// Start a note on an MPE output on a member channel of its own, taking the
+    /// oldest note's channel when every one is busy. The channel first gets the
+    /// expression the holding device last sent.
+    fn mpe_note_on(&mut self, index: usize, timestamp: Duration, note: u8, velocity: u8, holder: Holder) {
+        let Some(zone) = &self.mpe[index] else {
+            return;
+        };
+        if let Some(channel) = zone.to_steal() {
+            let stolen = self.notes.sounding().filter(|&(output, on, _)| output == index && on == channel).collect();
+            self.release(timestamp, stolen);
+        }
+        let Some(zone) = &mut self.mpe[index] else {
+            return;
+        };
+        // Even if no tracked note was holding it
+        if let Some(channel) = zone.to_steal() {
+            zone.release(channel);
+        }
+        let Some(channel) = zone.allocate(holder.0) else {
+            return;
+        };
+        for (bytes, voice) in zone.initial_expression(channel, holder.0) {
+            self.send_message(index, timestamp, &bytes, Some(&voice), None);
+        }
+        let bytes = [NOTE_ON | channel, note, velocity];
+        self.send_message(index, timestamp, &bytes, Voice::from_midi1(&bytes).as_ref(), Some(holder));
+    }
+
+    /// Announce an output's MPE zone: the MPE Configuration Message and the
+    /// member channels' pitch bend range.
+    fn configure_mpe(&mut self, index: usize, timestamp: Duration) {
+        let Some(zone) = &self.mpe[index] else {
+            return;
+        };
+        for message in zone.configuration() {
+            if let Some(recorder) = &mut self.recorder {
+                recorder.record(index, timestamp, &message);
+            }
+            let output = &mut self.outputs[index];
+            if !output.is_online() {
+                continue;
+            }
+            if let Err(e) = output.send(timestamp, &message) {
+                eprintln!("{e}");
+                return;
+            }
+        }
+    }
+
+    fn change_clock(&mut self, change: ClockChange) {
+        let Some(clock) = &mut self.clock else {
+            return;
+        };
+        let tempo = clock.tempo();
+        match change {
+            ClockChange::Restart => {
+                self.timers.retain(|timer| !matches!(timer, Timer::ClockTick));
+                self.timers.schedule(Instant::now(), Timer::ClockTick);
+            }
+            ClockChange::Tempo(tempo) => clock.set_tempo(tempo),
+            ClockChange::Step(bpm) => clock.set_tempo(clock.tempo() + bpm),
+            ClockChange::Nudge(bpm) => clock.set_nudge(bpm),
+            ClockChange::Tap => clock.tap(Instant::now()),
+        }
+        if (clock.tempo() - tempo).abs() >= 0.05 {
+            println!("Tempo {:.1} BPM", clock.tempo());
+        }
+    }
+
+    /// Send a clock tick and schedule the next one. Ticks are scheduled from when
+    /// the last was due rather than sent, so the tempo does not drift.
+    fn clock_tick(&mut self, due: Instant) {
+        let Some(clock) = &self.clock else {
+            return;
+        };
+        let interval = clock.interval();
+        let now = now();
+        for index in clock.outputs.clone() {
+            self.send(index, now, &[transport::CLOCK], None);
+        }
+        // After a stall, pick up from now instead of sending the missed ticks in a burst
+        let next = (due + interval).max(Instant::now());
+        self.timers.schedule(next, Timer::ClockTick);
+    }
+
+    /// Bring held chord `index` to the current voicing: end the notes it no
+    /// longer has and start its new ones, leaving the notes it keeps sounding.
+    fn voice_chord(&mut self, index: usize, timestamp: Duration) {
+        let chord = &self.chords[index];
+        let notes = chord.shape.notes(chord.root, self.voicing);
+        let ended: Vec<u8> = chord.notes.iter().copied().filter(|note| !notes.contains(note)).collect();
+        let started: Vec<u8> = notes.iter().copied().filter(|note| !chord.notes.contains(note)).collect();
+        let (holder, channel, outputs) = (chord.holder, chord.channel, chord.outputs.clone());
+        println!("Chord {} on {}, {}: {notes:?}", chord.shape.name, Pitch(chord.root), self.voicing);
+
+        let ended = self.notes.take_held(holder, &ended);
+        self.release(timestamp, ended);
+        for note in started {
+            for &output in &outputs {
+                if self.mpe[output].is_some() {
+                    self.mpe_note_on(output, timestamp, note, MIDI_MAX_VALUE, holder);
+                } else {
+                    let bytes = [NOTE_ON | channel, note, MIDI_MAX_VALUE];
+                    self.send_message(output, timestamp, &bytes, Voice::from_midi1(&bytes).as_ref(), Some(holder));
+                }
+            }
+        }
+        self.chords[index].notes = notes;
+    }
+
+    fn change_voicing(&mut self, control: VoicingControl, change: VoicingChange, timestamp: Duration) {
+        let max = control.max();
+        let value = self.voicing.get_mut(control);
+        let changed = match change {
+            VoicingChange::Next => (*value + 1) % (max + 1),
+            VoicingChange::Step(by) => {
+                u8::try_from((i32::from(*value) + by).clamp(0, i32::from(max))).unwrap_or(*value)
+            }
+            VoicingChange::Set(position) => nearest_step(position, max),
+        };
+        if changed == *value {
+            return;
+        }
+        *value = changed;
+        println!("Chord voicing: {}", self.voicing);
+        for index in 0..self.chords.len() {
+            self.voice_chord(index, timestamp);
+        }
+    }
+
+    /// Start macro `index`, or cancel it if it is still running.
+    fn toggle_macro(&mut self, index: usize, outputs: Vec<usize>, channel: u8) {
+        let name = self.macros[index].name.clone();
+        if let Some(run) = self.running_macros.remove(&index) {
+            self.timers.retain(|timer| !matches!(timer, Timer::MacroStep { index: running, .. } if *running == index));
+            let timestamp = now();
+            for (output, channel, note) in run.notes {
+                self.send(output, timestamp, &[NOTE_OFF | channel, note, 0], None);
+            }
+            println!("Cancelled macro {name}");
+            return;
+        }
+        println!("Running macro {name}");
+        let notes = BTreeSet::new();
+        self.running_macros.insert(index, MacroRun { outputs, channel, notes });
+        self.macro_step(index, 0, Instant::now());
+    }
+
+    /// Send the steps of macro `index` from `first` up to its next wait, which
+    /// schedules the rest relative to `due` so waits do not add up drift.
+    fn macro_step(&mut self, index: usize, first: usize, due: Instant) {
+        for step in first..self.macros[index].steps.len() {
+            let step_config = &self.macros[index].steps[step];
+            if let Some(delay) = step_config.delay() {
+                self.timers.schedule(due + delay, Timer::MacroStep { index, step: step + 1 });
+                return;
+            }
+            let Some(run) = self.running_macros.get_mut(&index) else {
+                return;
+            };
+            let messages = step_config.messages(run.channel);
+            let outputs = run.outputs.clone();
+            for message in &messages {
+                if let [status, note, velocity] = **message {
+                    let notes = outputs.iter().map(|&output| (output, status & 0x0F, note));
+                    match status & 0xF0 {
+                        NOTE_ON if velocity > 0 => run.notes.extend(notes),
+                        NOTE_ON | NOTE_OFF => notes.for_each(|note| {
+                            run.notes.remove(&note);
+                        }),
+                        _ => {}
+                    }
+                }
+            }
+            let timestamp = now();
+            for message in messages {
+                for &output in &outputs {
+                    self.send(output, timestamp, &message, None);
+                }
+            }
+        }
+        self.running_macros.remove(&index);
+    }
+
+    /// Stop every running macro where it is, leaving its notes to the caller.
+    fn cancel_macros(&mut self) {
+        self.running_macros.clear();
+        self.timers.retain(|timer| !matches!(timer, Timer::MacroStep { .. }));
+    }
+
+    /// How long the main loop may wait for events before calling `poll`.
+    pub fn timeout(&self) -> Duration {
+        self.timers
+            .next_deadline()
+            .map_or(MAX_WAIT, |at| at.saturating_duration_since(Instant::now()).min(MAX_WAIT))
+    }
+
+    /// Record everything sent from now on into a Standard MIDI File at `path`,
+    /// saving any recording already running first.
+    pub fn start_recording(&mut self, path: PathBuf) {
+        self.stop_recording();
+        self.recording_path.clone_from(&path);
+        self.recorder = Some(Recorder::new(path, now()));
+    }
+
+    /// Save the running recording, if any.
+    pub fn stop_recording(&mut self) {
+        if let Some(recorder) = self.recorder.take() {
+            if let Err(e) = recorder.finish(&self.output_names) {
+                eprintln!("{e:?}");
+            }
+        }
+    }
+
+    /// Stop and save, or start a new take without overwriting earlier ones.
+    fn toggle_recording(&mut self, timestamp: Duration) {
+        if self.recorder.is_some() {
+            self.stop_recording();
+        } else {
+            self.recorder = Some(Recorder::new(smf::unused_path(&self.recording_path), timestamp));
+        }
+    }
+
+    /// Swap in the mappings of a reloaded profile. A profile that changes its
+    /// devices, outputs, OSC outputs, inputs or clock is rejected, since those
+    /// need a restart.
+    pub fn load_mappings(&mut self, profile: &Profile) -> Result<()> {
+        let setup = Setup::of(profile);
+        let changed = [
+            (setup.devices != self.setup.devices, "devices"),
+            (setup.outputs != self.setup.outputs, "outputs"),
+            (setup.osc_outputs != self.setup.osc_outputs, "osc_outputs"),
+            (setup.inputs != self.setup.inputs, "inputs"),
+            (setup.clock != self.setup.clock, "clock"),
+        ];
+        if let Some((_, section)) = changed.iter().find(|(changed, _)| *changed) {
+            return Err(eyre!("Reloaded profile changes its {section}, which needs a restart"));
+        }
+        for config in &profile.macros {
+            config.validate()?;
+        }
+        let virtual_axes = profile
+            .virtual_axes
+            .iter()
+            .map(|axis| VirtualAxis::resolve(axis, profile, &self.setup))
+            .collect::<Result<_>>()?;
+        // Into a new set, so that a reload which fails leaves the running scripts alone
+        let mut scripts = Scripts::new();
+        let mappings = profile
+            .mappings
+            .iter()
+            .map(|mapping| Mapping::resolve(mapping, profile, &self.setup, &self.output_names, &mut scripts))
+            .collect::<Result<_>>()?;
+        scripts.keep_state(&self.scripts);
+        self.scripts = scripts;
+        self.cancel_macros();
+        self.release_all();
+        self.macros.clone_from(&profile.macros);
+        self.key = profile.key.as_ref().map(|key| Key::new(key.root, key.scale.clone()));
+        self.virtual_axes = virtual_axes;
+        self.mappings = mappings;
+        println!("Reloaded mappings");
+        Ok(())
+    }
+
+    /// Feedback from the DAW: controller values update what we know about the
+    /// output, notes can trigger the input's actions. Thru inputs also forward
+    /// every complete message to their outputs, so the joystick's messages only
+    /// ever go out between them.
+    fn handle_midi_in(&mut self, event: &MidiInEvent) {
+        let Some(route) = self.inputs.get_mut(event.input) else {
+            return;
+        };
+
+        // midir normally hands over whole SysEx messages, but hold on to a
+        // fragment until its end arrives rather than forward half of one.
+        // Realtime messages may arrive between fragments; any other status
+        // byte cuts the SysEx message short.
+        let message = match event.message.first() {
+            Some(&status) if status >= 0xF8 => event.message.clone(),
+            Some(&status) if !route.sysex.is_empty() && (status < 0x80 || status == 0xF7) => {
+                route.sysex.extend_from_slice(&event.message);
+                if route.sysex.last() != Some(&0xF7) {
+                    return;
+                }
+                std::mem::take(&mut route.sysex)
+            }
+            first => {
+                if let Some(&status) = first.filter(|_| !route.sysex.is_empty()) {
+                    eprintln!("Dropped a SysEx message cut short by status {status:02X}");
+                    route.sysex.clear();
+                }
+                if first == Some(&0xF0) && event.message.last() != Some(&0xF7) {
+                    route.sysex.clone_from(&event.message);
+                    return;
+                }
+                event.message.clone()
+            }
+        };
+
+        if !route.thru.is_empty() {
+            let forwarded = match message.first() {
+                Some(&status) if (0x80..0xF0).contains(&status) => route.channel_map[usize::from(status & 0x0F)]
+                    .map(|channel| [&[status & 0xF0 | channel], &message[1..]].concat()),
+                _ => Some(message.clone()),
+            };
+            if let Some(forwarded) = forwarded {
+                let now = now();
+                for index in route.thru.clone() {
+                    self.send(index, now, &forwarded, None);
+                }
+            }
+        }
+
+        let route = &self.inputs[event.input];
+        match *message.as_slice() {
+            [status, control, value] if status & 0xF0 == CONTROL_CHANGE => {
+                let (output, channel) = (route.output, status & 0x0F);
+                if self.controllers[output].insert((channel, control), value) != Some(value) {
+                    self.detached.insert((output, channel, control), None);
+                }
+            }
+            [status, note, velocity] if status & 0xF0 == NOTE_ON && velocity > 0 => {
+                let channel = status & 0x0F;
+                let layer = route
+                    .actions
+                    .iter()
+                    .filter(|action| action.note == note && action.channel.is_none_or(|only| only == channel))
+                    .map(|action| action.layer.clone())
+                    .next_back();
+                if let Some(layer) = layer {
+                    println!("Switched to layer {layer}");
+                    self.active_layer = Some(layer);
+                }
+            }
+            _ => {}
+        }
+
+        // The DAW talking to the Mackie Control surface we play
+        let output = route.output;
+        if let Some(state) = &mut self.mackie[output] {
+            let was_connected = state.connected;
+            let reply = state.receive(&message);
+            if state.connected != was_connected {
+                let status = if state.connected { "connected" } else { "disconnected" };
+                println!("Mackie Control {status} on {}", self.output_names[output]);
+            }
+            if let Some(reply) = reply {
+                self.send(output, now(), &reply, None);
+            }
+        }
+    }
+
+    /// Commands from the OSC server. Switching profiles is left to the main loop,
+    /// which owns the profile.
+    fn handle_osc_in(&mut self, OscInEvent { message, reply }: &OscInEvent) {
+        let args = &message.args;
+        match message.addr.as_str() {
+            osc_in::LAYER => {
+                self.active_layer = osc_in::arg_str(args, 0).filter(|layer| !layer.is_empty()).map(str::to_string);
+                match &self.active_layer {
+                    Some(layer) => println!("Switched to layer {layer}"),
+                    None => println!("Left all layers"),
+                }
+            }
+            address @ (osc_in::BUTTON | osc_in::AXIS) => {
+                let event_type = if address == osc_in::BUTTON { EventType::EV_KEY } else { EventType::EV_ABS };
+                let device = match args.first() {
+                    Some(OscType::String(name)) => {
+                        self.device_names.iter().position(|device| device.as_str() == name.as_str())
+                    }
+                    Some(&OscType::Int(index)) => {
+                        usize::try_from(index).ok().filter(|&index| index < self.device_names.len())
+                    }
+                    _ => None,
+                };
+                let code = osc_in::arg_str(args, 1).and_then(|name| EventCode::from_str(&event_type, name));
+                let value = match (event_type, args.get(2)) {
+                    (EventType::EV_ABS, Some(&OscType::Int(raw))) => Some(raw),
+                    (EventType::EV_ABS, _) => {
+                        osc_in::arg_number(args, 2).map(axis_position)
+                    }
+                    _ => osc_in::arg_number(args, 2).map(|value| i32::from(value >= 0.5)),
+                };
+                let (Some(device), Some(code), Some(value)) = (device, code, value) else {
+                    eprintln!("Ignoring OSC {address} {args:?}: expected device, {event_type} code and value");
+                    return;
+                };
+
+                // Injected events form a frame of their own
+                let now = now();
+                let secs = i64::try_from(now.as_secs()).unwrap_or_default();
+                let time = TimeVal::new(secs, i64::from(now.subsec_micros()));
+                for (code, value) in [(code, value), (EventCode::EV_SYN(EV_SYN::SYN_REPORT), 0)] {
+                    let event = InputEvent::new(&time, &code, value);
+                    self.handle_device(DeviceEvent { device, event });
+                }
+            }
+            osc_in::QUERY => reply.send(&self.state()),
+            osc_in::PROFILE => {}
+            address => eprintln!("Ignoring unknown OSC address {address}"),
+        }
+    }
+
+    /// Answer to a query: the active layer, whether we are recording, the tempo, the
+    /// key, every known controller value, the Mackie Control displays and every sounding note.
+    fn state(&self) -> OscPacket {
+        let message = |addr: &str, args| OscPacket::Message(OscMessage { addr: addr.to_string(), args });
+        let mut content = vec![
+            message("/js-midi/layer", vec![OscType::String(self.active_layer.clone().unwrap_or_default())]),
+            message("/js-midi/recording", vec![OscType::Bool(self.recorder.is_some())]),
+        ];
+        if let Some(clock) = &self.clock {
+            content.push(message("/js-midi/tempo", vec![OscType::Double(clock.tempo())]));
+        }
+        if let Some(key) = &self.key {
+            let args = vec![OscType::String(key.to_string()), OscType::Int(i32::from(key.root().0))];
+            content.push(message("/js-midi/key", args));
+        }
+        for (name, controllers) in self.output_names.iter().zip(&self.controllers) {
+            for (&(channel, control), &value) in controllers {
+                let args = [channel, control, value].map(|byte| OscType::Int(i32::from(byte)));
+                let args = [vec![OscType::String(name.clone())], args.to_vec()].concat();
+                content.push(message("/js-midi/controller", args));
+            }
+        }
+        for (name, state) in self.output_names.iter().zip(&self.mackie) {
+            if let Some(state) = state {
+                let args = vec![
+                    OscType::String(name.clone()),
+                    OscType::Bool(state.connected),
+                    OscType::String(state.lcd_line(0)),
+                    OscType::String(state.lcd_line(1)),
+                ];
+                content.push(message("/js-midi/mackie", args));
+            }
+        }
+        for (output, channel, note) in self.notes.sounding() {
+            let name = OscType::String(self.output_names[output].clone());
+            let args = vec![name, OscType::Int(i32::from(channel)), OscType::Int(i32::from(note))];
+            content.push(message("/js-midi/note", args));
+        }
+        OscPacket::Bundle(OscBundle {
+            timetag: OscTime { seconds: 0, fractional: 1 },
+            content,
+        })
+    }
+
+    /// Soft takeover: a controller the DAW moved stays detached from its axis
+    /// until the axis reaches, or sweeps past, the DAW's value.
+    fn picked_up(&mut self, index: usize, bytes: &[u8]) -> bool {
+        let [status, control, value] = *bytes else {
+            return true;
+        };
+        let key = (index, status & 0x0F, control);
+        let Some(&previous) = self.detached.get(&key) else {
+            return true;
+        };
+
+        let target = self.controllers[index].get(&(key.1, key.2)).copied().unwrap_or(value);
+        let swept = previous.is_some_and(|previous| (previous.min(value)..=previous.max(value)).contains(&target));
+        if value == target || swept {
+            self.detached.remove(&key);
+            true
+        } else {
+            self.detached.insert(key, Some(value));
+            false
+        }
+    }
+
+    /// Run the timers that are due and check on the outputs; call this at least
+    /// as often as `timeout` says, whether or not events arrive.
+    ///
+    /// An output that has come back is told its MPE zone again and gets the last
+    /// value of every controller sent to it, so the instrument matches the controls again.
+    pub fn poll(&mut self) {
+        while let Some((due, timer)) = self.timers.pop_due(Instant::now()) {
+            match timer {
+                Timer::ClockTick => self.clock_tick(due),
+                Timer::MacroStep { index, step } => self.macro_step(index, step, due),
+            }
+        }
+
+        if self.last_poll.elapsed() < POLL_INTERVAL {
+            return;
+        }
+        self.last_poll = Instant::now();
+        self.scripts.reload_changed();
+
+        let now = now();
+        for ((output, controllers), zone) in self.outputs.iter_mut().zip(&self.controllers).zip(&self.mpe) {
+            if !output.poll() {
+                continue;
+            }
+            let configuration = zone.as_ref().map(MpeZone::configuration).unwrap_or_default();
+            let controllers =
+                controllers.iter().map(|(&(channel, control), &value)| [CONTROL_CHANGE | channel, control, value]);
+            for message in configuration.into_iter().chain(controllers) {
+                if let Err(e) = output.send(now, &message) {
+                    eprintln!("{e}");
+                    break;
+                }
+            }
+        }
+    }
+
+    fn send(&mut self, index: usize, timestamp: Duration, bytes: &[u8], holder: Option<Holder>) {
+        self.send_message(index, timestamp, bytes, None, holder);
+    }
+
+    /// Send to one output, remembering controller values even while it is offline.
+    ///
+    /// Outputs that take MIDI 2.0 get `midi2` when there is one, the rest `bytes`;
+    /// our own bookkeeping and the recording always go by `bytes`.
+    fn send_message(
+        &mut self,
+        index: usize,
+        timestamp: Duration,
+        bytes: &[u8],
+        midi2: Option<&Voice>,
+        holder: Option<Holder>,
+    ) {
+        match *bytes {
+            // Channel mode messages (120 and up) are not controller values
+            [status, control, value] if status & 0xF0 == CONTROL_CHANGE && control < 120 => {
+                self.controllers[index].insert((status & 0x0F, control), value);
+            }
+            [status, control, _] if status & 0xF0 == CONTROL_CHANGE && control == MidiCC::ResetAllControllers as u8 => {
+                self.controllers[index].retain(|&(channel, _), _| channel != status & 0x0F);
+            }
+            // A surface's buttons are switches, not notes to keep track of
+            _ if self.mackie[index].is_some() => {}
+            _ => self.notes.observe(index, bytes, holder),
+        }
+        // A member channel is free again once its note has ended
+        if let (Some(zone), &[status, _, velocity]) = (&mut self.mpe[index], bytes) {
+            if status & 0xF0 == NOTE_OFF || (status & 0xF0 == NOTE_ON && velocity == 0) {
+                zone.release(status & 0x0F);
+            }
+        }
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record(index, timestamp, bytes);
+        }
+
+        let output = &mut self.outputs[index];
+        if output.is_online() {
+            let result = match midi2 {
+                Some(message) => output.send_midi2(timestamp, *message),
+                None if bytes.is_empty() => Ok(()),
+                None => output.send(timestamp, bytes),
+            };
+            if let Err(e) = result {
+                eprintln!("{e}");
+            }
+        }
+    }
+}
+
+impl Mapping {
+    fn resolve(
+        config: &MappingConfig,
+        profile: &Profile,
+        setup: &Setup,
+        output_names: &[String],
+        scripts: &mut Scripts,
+    ) -> Result<Self> {
+        let device = config
+            .device
+            .as_ref()
+            .map(|name| setup.device(name).ok_or_else(|| eyre!("Mapping refers to unknown device {name}")))
+            .transpose()?;
+
+        let (event_type, name) = match &config.source {
+            Source::Axis(name) => (Some(EventType::EV_ABS), name),
+            Source::Key(name) => (Some(EventType::EV_KEY), name),
+            Source::VirtualAxis(name) => (None, name),
+        };
+        let trigger = match event_type {
+            Some(event_type) => {
+                Trigger::Code(EventCode::from_str(&event_type, name).ok_or_else(|| eyre!("Unknown event code {name}"))?)
+            }
+            None => Trigger::Virtual(
+                profile
+                    .virtual_axes
+                    .iter()
+                    .position(|axis| axis.name == *name)
+                    .ok_or_else(|| eyre!("Mapping refers to unknown virtual axis {name}"))?,
+            ),
+        };
+
+        let outputs = if let Target::Osc(_) = config.target {
+            let osc_names: Vec<_> = setup.osc_outputs.iter().map(|output| output.name.clone()).collect();
+            if osc_names.is_empty() {
+                return Err(eyre!("OSC mapping for {name} but the profile has no osc_outputs"));
+            }
+            config.outputs.iter().map(|name| output_index(&osc_names, name)).collect::<Result<_>>()?
+        } else {
+            config.outputs.iter().map(|name| output_index(output_names, name)).collect::<Result<_>>()?
+        };
+
+        if config.channel > 15 {
+            return Err(eyre!("MIDI channel {} out of range 0-15", config.channel));
+        }
+
+        if let Target::Note(note) | Target::NotePitch(note) | Target::NoteController { note, .. } = config.target {
+            if note > 127 {
+                return Err(eyre!("MIDI note {note} out of range 0-127"));
+            }
+        }
+
+        if let Target::MackieFader(fader) = config.target {
+            if usize::from(fader) >= mackie::FADERS {
+                return Err(eyre!("Mackie fader {fader} out of range 0-8"));
+            }
+        }
+
+        let needs_clock =
+            matches!(config.target, Target::Tempo(_) | Target::TempoNudge(_) | Target::Action(Action::TapTempo));
+        if needs_clock && setup.clock.is_none() {
+            return Err(eyre!("Tempo mapping for {name} but the profile has no clock"));
+        }
+        let needs_key = matches!(config.target, Target::Degree(_) | Target::Chord(_) | Target::Transpose(_));
+        if needs_key && profile.key.is_none() {
+            return Err(eyre!("Scale mapping for {name} but the profile has no key"));
+        }
+
+        let dump = match &config.target {
+            Target::Sysex(template) => {
+                template.validate()?;
+                Vec::new()
+            }
+            Target::Syx(path) => sysex::load_syx(path)?,
+            _ => Vec::new(),
+        };
+        let script = match &config.target {
+            Target::Script(target) => Some(scripts.load(&target.file, &target.function)?),
+            _ => None,
+        };
+        let sequence = match &config.target {
+            Target::Macro(name) => Some(
+                profile
+                    .macros
+                    .iter()
+                    .position(|config| config.name == *name)
+                    .ok_or_else(|| eyre!("Mapping refers to unknown macro {name}"))?,
+            ),
+            _ => None,
+        };
+
+        Ok(Self {
+            device,
+            layer: config.layer.clone(),
+            trigger,
+            target: config.target.clone(),
+            channel: Channel::new(config.channel),
+            outputs,
+            takeover: config.takeover,
+            dump,
+            script,
+            sequence,
+        })
+    }
+}
+
+impl VirtualAxis {
+    fn resolve(config: &VirtualAxisConfig, profile: &Profile, setup: &Setup) -> Result<Self> {
+        let device = config
+            .device
+            .as_ref()
+            .map(|name| {
+                setup.device(name).ok_or_else(|| eyre!("Virtual axis {} refers to unknown device {name}", config.name))
+            })
+            .transpose()?;
+        for code in config.combine.components() {
+            let known = [EventType::EV_ABS, EventType::EV_KEY]
+                .iter()
+                .any(|event_type| EventCode::from_str(event_type, code).is_some());
+            if !known {
+                return Err(eyre!("Virtual axis {} uses unknown event code {code}", config.name));
+            }
+        }
+        if profile.virtual_axes.iter().filter(|axis| axis.name == config.name).count() > 1 {
+            return Err(eyre!("Virtual axis {} is defined more than once", config.name));
+        }
+        Ok(Self {
+            name: config.name.clone(),
+            device,
+            combine: config.combine.clone(),
+            values: BTreeMap::new(),
+        })
+    }
+}
+
+fn output_index(names: &[String], name: &str) -> Result<usize> {
+    names
+        .iter()
+        .position(|output| output == name)
+        .ok_or_else(|| eyre!("Unknown output {name}"))
+}
+
+fn now() -> Duration {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default()
+}
+
+/// Time of an input event as an offset from the epoch.
+fn timestamp(time: &TimeVal) -> Duration {
+    let secs = u64::try_from(time.tv_sec).unwrap_or_default();
+    let micros = u64::try_from(time.tv_usec).unwrap_or_default();
+    Duration::from_secs(secs) + Duration::from_micros(micros)
+}
+
+fn render(message: MidiMessage) -> Vec<u8> {
+    let mut buf = [0; 3];
+    let len = message.render_slice(&mut buf);
+    buf[..len].to_vec()
+}
+
+/// A full velocity note on, in both protocols.
+fn note_on(channel: Channel, note: u8) -> (Vec<u8>, Option<Voice>) {
+    let message = MidiMessage::NoteOn(channel, Note::new(note), Value7::new(127));
+    let voice = Voice::NoteOn {
+        channel: channel.into(),
+        note,
+        velocity: u16::MAX,
+    };
+    (render(message), Some(voice))
+}
+
+/// An axis position as a MIDI 2.0 32-bit value.
+fn axis_value(value: i32) -> u32 {
+    u32::try_from(value.clamp(0, 0xFFFF)).unwrap_or_default() * 0x0001_0001
+}
+
+/// A reported value as 0.0-1.0: axes over their range, hats from -1 to 1,
+/// keys pressed or not.
+fn normalise(code: &str, value: i32) -> f64 {
+    if code.starts_with("ABS_HAT") {
+        f64::from(value.clamp(-1, 1) + 1) / 2.0
+    } else if code.starts_with("ABS_") {
+        f64::from(value) / f64::from(MAX_JOYSTICK_VALUE)
+    } else {
+        f64::from(value.min(1))
+    }
+}
+
+fn map_value(value: i32) -> u8 {
+    let scaled = value.clamp(0, 0xFFFF) * i32::from(MIDI_MAX_VALUE) / 0xFFFF;
+    u8::try_from(scaled).unwrap_or(MIDI_MAX_VALUE)
+}
+
+/// A 0.0-1.0 position as an axis value, saturating outside that range.
+#[allow(clippy::cast_possible_truncation)]
+fn axis_position(position: f64) -> i32 {
+    (position * f64::from(MAX_JOYSTICK_VALUE)).round() as i32
+}
+
+/// The step of `0..=max` nearest a 0.0-1.0 position; in range once clamped.
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn nearest_step(position: f64, max: u8) -> u8 {
+    (position.clamp(0.0, 1.0) * f64::from(max)).round() as u8
+}
+
+/// An axis or key value as an OSC float; axis values fit in its 24-bit mantissa.
+#[allow(clippy::cast_precision_loss)]
+const fn osc_float(value: i32) -> f32 {
+    value as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::MemorySink;
+    use evdev_rs::enums::EV_KEY;
+
+    /// An engine running `profile`, with a single output that records what it gets.
+    fn engine(profile: &str) -> (Engine, MemorySink) {
+        let profile: Profile = toml::from_str(profile).expect("test profile parses");
+        let sink = MemorySink::new("out");
+        let outputs: Vec<(String, Box<dyn MidiSink>)> = vec![("out".to_string(), Box::new(sink.clone()))];
+        let engine = Engine::with_outputs(&profile, outputs).expect("test profile resolves");
+        (engine, sink)
+    }
+
+    fn input(engine: &mut Engine, device: usize, code: EventCode, value: i32) {
+        let event = InputEvent::new(&TimeVal::new(1, 500), &code, value);
+        engine.handle(Event::Device(DeviceEvent { device, event }));
+    }
+
+    fn axis(engine: &mut Engine, code: EV_ABS, value: i32) {
+        input(engine, 0, EventCode::EV_ABS(code), value);
+    }
+
+    fn key(engine: &mut Engine, device: usize, code: EV_KEY, value: i32) {
+        input(engine, device, EventCode::EV_KEY(code), value);
+    }
+
+    fn end_frame(engine: &mut Engine) {
+        input(engine, 0, EventCode::EV_SYN(EV_SYN::SYN_REPORT), 0);
+    }
+
+    fn sent(sink: &MemorySink) -> Vec<Vec<u8>> {
+        sink.messages().into_iter().map(|message| message.bytes).collect()
+    }
+
+    const TWO_KEYS: &str = r#"
+        [[devices]]
+        path = "/dev/input/left"
+
+        [[devices]]
+        path = "/dev/input/right"
+
+        [[mappings]]
+        key = "BTN_TRIGGER"
+        note = 60
+
+        [[mappings]]
+        key = "BTN_THUMB"
+        note = 62
+    "#;
+
+    #[test]
+    fn sysex_fragments_are_joined_around_realtime_messages() {
+        let (mut engine, sink) = engine(
+            r#"
+            [[inputs]]
+            virtual_port = "in"
+            thru = ["out"]
+            "#,
+        );
+        // A clock tick inside a SysEx message, then one cut short by a note
+        let fragments: [&[u8]; 6] =
+            [&[0xF0, 0x43, 0x10], &[0xF8], &[0x4C, 0x00], &[0x7F, 0xF7], &[0xF0, 0x01], &[0x90, 60, 100]];
+        for message in fragments {
+            engine.handle(Event::MidiIn(MidiInEvent {
+                input: 0,
+                message: message.to_vec(),
+            }));
+        }
+        assert_eq!(sent(&sink), [vec![0xF8], vec![0xF0, 0x43, 0x10, 0x4C, 0x00, 0x7F, 0xF7], vec![0x90, 60, 100]]);
+    }
+
+    #[test]
+    fn reload_keeps_devices_and_clock() {
+        let (mut engine, sink) = engine(TWO_KEYS);
+        let reload = |profile: &str| toml::from_str::<Profile>(profile).expect("test profile parses");
+
+        let remapped = TWO_KEYS.replace("note = 62", "note = 64");
+        assert!(engine.load_mappings(&reload(&remapped)).is_ok());
+        key(&mut engine, 1, EV_KEY::BTN_THUMB, 1);
+        assert_eq!(sent(&sink), [[0x90, 64, 127]]);
+
+        let renamed = TWO_KEYS.replace("/dev/input/right", "/dev/input/pedals");
+        assert!(engine.load_mappings(&reload(&renamed)).is_err());
+        let clocked = format!("[clock]\ntempo = 100.0\n{TWO_KEYS}");
+        assert!(engine.load_mappings(&reload(&clocked)).is_err());
+        let rerouted = format!("{TWO_KEYS}\n[[outputs]]\nname = \"out\"\nvirtual_port = \"synth\"");
+        assert!(engine.load_mappings(&reload(&rerouted)).is_err());
+        let listening = format!("{TWO_KEYS}\n[[inputs]]\nvirtual_port = \"daw\"");
+        assert!(engine.load_mappings(&reload(&listening)).is_err());
+
+        // The rejected reloads left the mappings as they were
+        key(&mut engine, 0, EV_KEY::BTN_TRIGGER, 1);
+        assert_eq!(sent(&sink), [[0x90, 64, 127], [0x90, 60, 127]]);
+    }
+
+    #[test]
+    fn port_needs_an_output_to_replace() {
+        let profile: Profile = toml::from_str(
+            r#"
+            [[devices]]
+            path = "/dev/input/stick"
+            virtual_port = "stick"
+            "#,
+        )
+        .expect("test profile parses");
+        let port = Destination::Virtual("synth".to_string());
+        let error = Engine::new(&profile, Some(port), true).err().expect("--port is not dropped");
+        assert_eq!(error.to_string(), "--port given but the profile has nowhere to use it");
+    }
+
+    #[test]
+    fn input_channels_must_be_in_range() {
+        let route = |filter: &str| {
+            let config: InputConfig =
+                toml::from_str(&format!("virtual_port = \"in\"\n{filter}")).expect("test input parses");
+            InputRoute::resolve(&config, &["out".to_string()])
+        };
+        let channel_map = route("channels = [0, 1]\nremap = [[1, 9]]").expect("channels in range").channel_map;
+        assert_eq!(channel_map[..3], [Some(0), Some(9), None]);
+        for filter in ["channels = [16]", "remap = [[16, 0]]", "remap = [[0, 16]]"] {
+            let error = route(filter).err().expect("channel 16 is rejected");
+            assert_eq!(error.to_string(), "MIDI channel 16 out of range 0-15", "{filter}");
+        }
+    }
+
+    #[test]
+    fn mpe_expression_reaches_device_or_zone() {
+        let (mut engine, sink) = engine(
+            r#"
+            [[devices]]
+            name = "stick"
+            path = "/dev/input/stick"
+
+            [[devices]]
+            name = "throttle"
+            path = "/dev/input/throttle"
+
+            [[outputs]]
+            name = "out"
+            virtual_port = "out"
+            mpe = { zone = "lower" }
+
+            [[mappings]]
+            device = "stick"
+            key = "BTN_TRIGGER"
+            note = 60
+
+            [[mappings]]
+            axis = "ABS_Z"
+            mpe = "timbre"
+
+            [[mappings]]
+            axis = "ABS_THROTTLE"
+            mpe = { expression = "pressure", scope = "zone" }
+            "#,
+        );
+        key(&mut engine, 0, EV_KEY::BTN_TRIGGER, 1);
+        sink.clear();
+
+        // The throttle's timbre only shapes its own notes, of which it has none
+        input(&mut engine, 1, EventCode::EV_ABS(EV_ABS::ABS_Z), 0xFFFF);
+        assert_eq!(sent(&sink), Vec::<Vec<u8>>::new());
+        input(&mut engine, 1, EventCode::EV_ABS(EV_ABS::ABS_THROTTLE), 0xFFFF);
+        assert_eq!(sent(&sink), [[0xD1, 127]]);
+        input(&mut engine, 0, EventCode::EV_ABS(EV_ABS::ABS_Z), 0xFFFF);
+        assert_eq!(sent(&sink), [vec![0xD1, 127], vec![0xB1, 74, 127]]);
+    }
+
+    #[test]
+    fn cc_scales_axis_range_to_7_bits() {
+        let (mut engine, sink) = engine(
+            r#"
+            [[devices]]
+            path = "/dev/input/stick"
+
+            [[mappings]]
+            axis = "ABS_X"
+            cc = 7
+            channel = 2
+            "#,
+        );
+        for value in [0, 0x8000, 0xFFFF] {
+            axis(&mut engine, EV_ABS::ABS_X, value);
+        }
+        assert_eq!(sent(&sink), [[0xB2, 7, 0], [0xB2, 7, 64], [0xB2, 7, 127]]);
+        assert!(sink.messages().iter().all(|message| message.timestamp == Duration::from_micros(1_000_500)));
+    }
+
+    #[test]
+    fn only_buttons_play_notes() {
+        let (mut engine, sink) = engine(
+            r#"
+            [[devices]]
+            path = "/dev/input/stick"
+
+            [[mappings]]
+            axis = "ABS_HAT0X"
+            note = 60
+
+            [[mappings]]
+            key = "BTN_TRIGGER"
+            note = 62
+            "#,
+        );
+        axis(&mut engine, EV_ABS::ABS_HAT0X, 1);
+        key(&mut engine, 0, EV_KEY::BTN_TRIGGER, 1);
+        assert_eq!(sent(&sink), [[0x90, 62, 127]]);
+    }
+
+    #[test]
+    fn difference_axis_moves_against_its_second_component() {
+        let (mut engine, sink) = engine(
+            r#"
+            [[devices]]
+            path = "/dev/input/stick"
+
+            [[virtual_axes]]
+            name = "brakes"
+            difference = ["ABS_Y", "ABS_X"]
+
+            [[mappings]]
+            virtual_axis = "brakes"
+            cc = 1
+            "#,
+        );
+        axis(&mut engine, EV_ABS::ABS_X, 0);
+        axis(&mut engine, EV_ABS::ABS_Y, 0);
+        end_frame(&mut engine);
+        axis(&mut engine, EV_ABS::ABS_X, 0xFFFF);
+        end_frame(&mut engine);
+        axis(&mut engine, EV_ABS::ABS_X, 0);
+        axis(&mut engine, EV_ABS::ABS_Y, 0xFFFF);
+        end_frame(&mut engine);
+        assert_eq!(sent(&sink), [[0xB0, 1, 64], [0xB0, 1, 0], [0xB0, 1, 127]]);
+    }
+
+    #[test]
+    fn hats_are_normalised_from_their_three_positions() {
+        let (mut engine, sink) = engine(
+            r#"
+            [[devices]]
+            path = "/dev/input/stick"
+
+            [[virtual_axes]]
+            name = "trim"
+            difference = ["ABS_HAT0X", "ABS_HAT0Y"]
+
+            [[mappings]]
+            virtual_axis = "trim"
+            cc = 1
+            "#,
+        );
+        axis(&mut engine, EV_ABS::ABS_HAT0X, 0);
+        axis(&mut engine, EV_ABS::ABS_HAT0Y, 0);
+        end_frame(&mut engine);
+        for value in [1, -1] {
+            axis(&mut engine, EV_ABS::ABS_HAT0X, value);
+            end_frame(&mut engine);
+        }
+        axis(&mut engine, EV_ABS::ABS_HAT0Y, 1);
+        end_frame(&mut engine);
+        assert_eq!(sent(&sink), [[0xB0, 1, 64], [0xB0, 1, 95], [0xB0, 1, 32], [0xB0, 1, 0]]);
+    }
+
+    #[test]
+    fn released_key_ends_only_its_own_note() {
+        let (mut engine, sink) = engine(TWO_KEYS);
+        key(&mut engine, 0, EV_KEY::BTN_TRIGGER, 1);
+        key(&mut engine, 0, EV_KEY::BTN_THUMB, 1);
+        // Key repeats neither retrigger nor end the note
+        key(&mut engine, 0, EV_KEY::BTN_TRIGGER, 2);
+        key(&mut engine, 0, EV_KEY::BTN_TRIGGER, 0);
+        assert_eq!(sent(&sink), [[0x90, 60, 127], [0x90, 62, 127], [0x80, 60, 0]]);
+    }
+
+    #[test]
+    fn disconnect_releases_the_devices_held_notes() {
+        let (mut engine, sink) = engine(TWO_KEYS);
+        key(&mut engine, 0, EV_KEY::BTN_TRIGGER, 1);
+        key(&mut engine, 1, EV_KEY::BTN_THUMB, 1);
+        sink.clear();
+
+        engine.handle(Event::Disconnected(Disconnected(0)));
+        assert_eq!(sent(&sink), [[0x80, 60, 0]]);
+        key(&mut engine, 1, EV_KEY::BTN_THUMB, 0);
+        assert_eq!(sent(&sink), [[0x80, 60, 0], [0x80, 62, 0]]);
+    }
+}
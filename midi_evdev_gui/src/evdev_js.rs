@@ -1,4 +1,3 @@
-use evdev_rs::enums::*;
 use evdev_rs::Device;
 use std::fs::File;
 
@@ -9,7 +8,7 @@ fn main() {
 
     loop {
         if let Ok((_, ev)) = device.next_event(evdev_rs::ReadFlag::NORMAL) {
-            println!("{:?}", ev);
+            println!("{ev:?}");
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,117 @@
+use crate::profile::DeviceConfig;
+use color_eyre::eyre::{Result, WrapErr};
+use evdev_rs::{Device, GrabMode, InputEvent, ReadFlag, ReadStatus};
+use std::fs::File;
+use std::io;
+use std::path::PathBuf;
+use std::sync::mpsc::Sender;
+use std::thread::{self, JoinHandle};
+
+/// An opened evdev device, optionally grabbed for exclusive access.
+///
+/// The grab is released when the device is dropped. If the process dies without
+/// unwinding (Ctrl-C, or a panic with `panic = 'abort'`) the kernel releases it
+/// anyway when the file descriptor is closed.
+pub struct InputDevice {
+    device: Device,
+    path: PathBuf,
+    grabbed: bool,
+}
+
+impl InputDevice {
+    pub fn open(config: &DeviceConfig) -> Result<Self> {
+        let file = File::open(&config.path)
+            .wrap_err_with(|| format!("Failed to open device file {}", config.path.display()))?;
+        let mut device = Device::new_from_file(file)
+            .wrap_err_with(|| format!("Failed to create device from {}", config.path.display()))?;
+
+        if config.grab {
+            device
+                .grab(GrabMode::Grab)
+                .wrap_err_with(|| format!("Failed to grab {}", config.path.display()))?;
+        }
+
+        Ok(Self {
+            device,
+            path: config.path.clone(),
+            grabbed: config.grab,
+        })
+    }
+
+    pub fn next_event(&self) -> io::Result<(ReadStatus, InputEvent)> {
+        self.device.next_event(ReadFlag::NORMAL | ReadFlag::BLOCKING)
+    }
+
+    /// Drain the events libevdev synthesises after a `SYN_DROPPED`, bringing our
+    /// view of the device back in line with the kernel's.
+    fn resync<T: From<DeviceEvent>>(&self, device: usize, tx: &Sender<T>) -> bool {
+        while let Ok((ReadStatus::Sync, event)) = self.device.next_event(ReadFlag::SYNC) {
+            if tx.send(DeviceEvent { device, event }.into()).is_err() {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+impl Drop for InputDevice {
+    fn drop(&mut self) {
+        if self.grabbed {
+            if let Err(e) = self.device.grab(GrabMode::Ungrab) {
+                eprintln!("Failed to release grab on {}: {:?}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+/// An input event tagged with the index of the profile device it came from.
+#[derive(Debug, Clone)]
+pub struct DeviceEvent {
+    pub device: usize,
+    pub event: InputEvent,
+}
+
+/// Sent once a device's reader has given up on it, usually because it was unplugged.
+#[derive(Debug, Clone, Copy)]
+pub struct Disconnected(pub usize);
+
+/// Start one blocking reader thread per device, all feeding the same queue.
+///
+/// Each thread owns its device (and its grab) and exits when the device goes away
+/// or the receiving end of the queue is dropped.
+pub fn spawn_readers<T>(devices: Vec<InputDevice>, tx: &Sender<T>) -> Vec<JoinHandle<()>>
+where
+    T: From<DeviceEvent> + From<Disconnected> + Send + 'static,
+{
+    devices
+        .into_iter()
+        .enumerate()
+        .map(|(index, device)| {
+            let tx = tx.clone();
+            thread::spawn(move || read_device(index, &device, &tx))
+        })
+        .collect()
+}
+
+fn read_device<T: From<DeviceEvent> + From<Disconnected>>(index: usize, device: &InputDevice, tx: &Sender<T>) {
+    loop {
+        match device.next_event() {
+            Ok((ReadStatus::Success, event)) => {
+                if tx.send(DeviceEvent { device: index, event }.into()).is_err() {
+                    return;
+                }
+            }
+            Ok((ReadStatus::Sync, event)) => {
+                if tx.send(DeviceEvent { device: index, event }.into()).is_err() || !device.resync(index, tx) {
+                    return;
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => {
+                eprintln!("Error reading {}: {:?}", device.path.display(), e);
+                let _ = tx.send(Disconnected(index).into());
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,285 @@
+use midi_types::status::{CONTROL_CHANGE, NOTE_ON, PITCH_BEND_CHANGE};
+use serde::Deserialize;
+use std::fmt;
+
+/// SysEx header of a Mackie Control main unit.
+const HEADER: [u8; 5] = [0xF0, 0x00, 0x00, 0x66, 0x14];
+/// What we answer the DAW's device query with.
+const SERIAL: [u8; 7] = *b"JSMIDI1";
+/// Fader channels 0-7 are the strips, 8 the master fader.
+pub const FADERS: usize = 9;
+/// Two lines of 56 characters.
+pub const LCD_WIDTH: usize = 56;
+
+/// CCs 0x40-0x49 write the timecode / BBT display, rightmost digit first.
+const TIMECODE: u8 = 0x40;
+
+/// Buttons, by the note number the surface sends for them.
+const BUTTONS: &[(&str, u8)] = &[
+    ("bank_left", 46),
+    ("bank_right", 47),
+    ("channel_left", 48),
+    ("channel_right", 49),
+    ("flip", 50),
+    ("global_view", 51),
+    ("name_value", 52),
+    ("smpte_beats", 53),
+    ("shift", 70),
+    ("option", 71),
+    ("control", 72),
+    ("alt", 73),
+    ("read", 74),
+    ("write", 75),
+    ("trim", 76),
+    ("touch", 77),
+    ("latch", 78),
+    ("group", 79),
+    ("save", 80),
+    ("undo", 81),
+    ("cancel", 82),
+    ("enter", 83),
+    ("marker", 84),
+    ("nudge", 85),
+    ("cycle", 86),
+    ("drop", 87),
+    ("replace", 88),
+    ("click", 89),
+    ("solo", 90),
+    ("rewind", 91),
+    ("fast_forward", 92),
+    ("stop", 93),
+    ("play", 94),
+    ("record", 95),
+    ("up", 96),
+    ("down", 97),
+    ("left", 98),
+    ("right", 99),
+    ("zoom", 100),
+    ("scrub", 101),
+];
+
+/// Buttons repeated on each of the 8 strips, named `select_1` to `select_8` and
+/// so on, by the note number of strip 1.
+const STRIP_BUTTONS: &[(&str, u8)] = &[("rec", 0), ("solo", 8), ("mute", 16), ("select", 24), ("vpot", 32)];
+
+/// A button of the surface, e.g. `"play"`, `"bank_right"` or `"select_3"`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Button(pub u8);
+
+impl Button {
+    pub fn name(self) -> String {
+        if let Some((name, _)) = BUTTONS.iter().find(|&&(_, note)| note == self.0) {
+            return (*name).to_string();
+        }
+        STRIP_BUTTONS
+            .iter()
+            .find(|&&(_, first)| (first..first + 8).contains(&self.0))
+            .map_or_else(|| format!("button_{}", self.0), |(name, first)| format!("{name}_{}", self.0 - first + 1))
+    }
+
+    /// Switch messages are note ons, with velocity 0 for the release.
+    pub const fn message(self, pressed: bool) -> [u8; 3] {
+        [NOTE_ON, self.0, if pressed { 0x7F } else { 0 }]
+    }
+}
+
+impl TryFrom<String> for Button {
+    type Error = UnknownButton;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        if let Some(&(_, note)) = BUTTONS.iter().find(|(known, _)| *known == name) {
+            return Ok(Self(note));
+        }
+        let strip = name.rsplit_once('_').and_then(|(group, strip)| {
+            let strip: u8 = strip.parse().ok().filter(|strip| (1..=8).contains(strip))?;
+            let &(_, first) = STRIP_BUTTONS.iter().find(|(known, _)| *known == group)?;
+            Some(Self(first + strip - 1))
+        });
+        strip.ok_or(UnknownButton(name))
+    }
+}
+
+impl fmt::Display for Button {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name())
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownButton(String);
+
+impl fmt::Display for UnknownButton {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown Mackie Control button {}", self.0)
+    }
+}
+
+impl std::error::Error for UnknownButton {}
+
+/// Move fader `fader` (0-7, 8 for master) to a 14-bit position.
+pub const fn fader_message(fader: u8, position: u16) -> [u8; 3] {
+    [PITCH_BEND_CHANGE | fader, (position & 0x7F) as u8, (position >> 7 & 0x7F) as u8]
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Led {
+    #[default]
+    Off,
+    On,
+    Flashing,
+}
+
+/// What the DAW has told the surface: where its motor faders are, which LEDs
+/// are lit and what the displays show.
+#[derive(Debug, Clone)]
+pub struct MackieState {
+    /// Whether the DAW has completed the handshake.
+    pub connected: bool,
+    /// 14-bit positions.
+    pub faders: [u16; FADERS],
+    /// By button note number.
+    pub leds: [Led; 128],
+    /// Both LCD lines, one after the other.
+    pub lcd: [u8; 2 * LCD_WIDTH],
+    /// The timecode / BBT display, leftmost digit first.
+    pub timecode: [u8; 10],
+}
+
+impl Default for MackieState {
+    fn default() -> Self {
+        Self {
+            connected: false,
+            faders: [0; FADERS],
+            leds: [Led::Off; 128],
+            lcd: [b' '; 2 * LCD_WIDTH],
+            timecode: [b' '; 10],
+        }
+    }
+}
+
+impl MackieState {
+    /// Take in a message from the DAW, returning what the handshake needs us to answer.
+    pub fn receive(&mut self, message: &[u8]) -> Option<Vec<u8>> {
+        match *message {
+            [status, lsb, msb] if status & 0xF0 == PITCH_BEND_CHANGE && usize::from(status & 0x0F) < FADERS => {
+                self.faders[usize::from(status & 0x0F)] = u16::from(msb) << 7 | u16::from(lsb);
+            }
+            [NOTE_ON, note, velocity] if note < 128 => {
+                self.leds[usize::from(note)] = match velocity {
+                    0 => Led::Off,
+                    1 => Led::Flashing,
+                    _ => Led::On,
+                };
+            }
+            [CONTROL_CHANGE, control, value] if (TIMECODE..TIMECODE + 10).contains(&control) => {
+                // Bit 6 is the digit's decimal point; the rest is the character
+                let digit = usize::from(control - TIMECODE);
+                self.timecode[9 - digit] = match value & 0x3F {
+                    character @ 0x00..=0x1F => character + 0x40,
+                    character => character,
+                };
+            }
+            _ => return self.receive_sysex(message),
+        }
+        None
+    }
+
+    fn receive_sysex(&mut self, message: &[u8]) -> Option<Vec<u8>> {
+        let body = message.strip_prefix(&HEADER)?.strip_suffix(&[0xF7])?;
+        match body {
+            // Device query: say who we are, with a challenge the DAW is meant to answer
+            [0x00, ..] => Some(sysex(&[&[0x01][..], &SERIAL, &[0; 4]].concat())),
+            // Host connection reply: nothing to check, confirm it
+            [0x02, ..] => {
+                self.connected = true;
+                Some(sysex(&[&[0x03], &SERIAL[..]].concat()))
+            }
+            // Version request
+            [0x13, ..] => Some(sysex(&[&[0x14], &b"V1.00"[..]].concat())),
+            // Go offline
+            [0x0F, 0x7F] => {
+                self.connected = false;
+                None
+            }
+            [0x12, offset, text @ ..] => {
+                let offset = usize::from(*offset).min(self.lcd.len());
+                let end = (offset + text.len()).min(self.lcd.len());
+                self.lcd[offset..end].copy_from_slice(&text[..end - offset]);
+                None
+            }
+            // Faders to minimum
+            [0x61] => {
+                self.faders = [0; FADERS];
+                None
+            }
+            // All LEDs off
+            [0x62] => {
+                self.leds = [Led::Off; 128];
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// One LCD line, 0 or 1, as text.
+    pub fn lcd_line(&self, line: usize) -> String {
+        let start = line.min(1) * LCD_WIDTH;
+        String::from_utf8_lossy(&self.lcd[start..start + LCD_WIDTH]).into_owned()
+    }
+}
+
+fn sysex(body: &[u8]) -> Vec<u8> {
+    [&HEADER[..], body, &[0xF7]].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handshake_answers_query_connection_and_version() {
+        let mut state = MackieState::default();
+        let query = state.receive(&sysex(&[0x00])).expect("query is answered");
+        assert_eq!(query, sysex(&[&[0x01][..], b"JSMIDI1", &[0, 0, 0, 0]].concat()));
+
+        let reply = state.receive(&sysex(&[&[0x02][..], b"JSMIDI1", &[1, 2, 3, 4]].concat()));
+        assert_eq!(reply, Some(sysex(&[&[0x03][..], b"JSMIDI1"].concat())));
+        assert!(state.connected);
+
+        assert_eq!(state.receive(&sysex(&[0x13, 0x00])), Some(sysex(&[&[0x14][..], b"V1.00"].concat())));
+        assert_eq!(state.receive(&sysex(&[0x0F, 0x7F])), None);
+        assert!(!state.connected);
+    }
+
+    #[test]
+    fn lcd_writes_land_at_their_offset() {
+        let mut state = MackieState::default();
+        assert_eq!(state.receive(&sysex(&[&[0x12, 3][..], b"Kick"].concat())), None);
+        assert_eq!(&state.lcd_line(0)[..8], "   Kick ");
+        // The second line starts at offset 56, and writes stop at the end of it
+        state.receive(&sysex(&[&[0x12, 110][..], b"Snare"].concat()));
+        assert!(state.lcd_line(1).ends_with("Sn"));
+    }
+
+    #[test]
+    fn timecode_digits_fill_from_the_right() {
+        let mut state = MackieState::default();
+        // '1', then 'A' as 0x01, then '2' with its decimal point
+        for (digit, value) in [(0, 0x31), (1, 0x01), (2, 0x72)] {
+            assert_eq!(state.receive(&[CONTROL_CHANGE, TIMECODE + digit, value]), None);
+        }
+        assert_eq!(&state.timecode, b"       2A1");
+    }
+
+    #[test]
+    fn faders_are_14_bit_pitch_bends() {
+        assert_eq!(fader_message(0, 0), [0xE0, 0x00, 0x00]);
+        assert_eq!(fader_message(8, 0x3FFF), [0xE8, 0x7F, 0x7F]);
+        assert_eq!(fader_message(3, 0x2001), [0xE3, 0x01, 0x40]);
+
+        let mut state = MackieState::default();
+        state.receive(&fader_message(8, 0x1234));
+        assert_eq!(state.faders[8], 0x1234);
+    }
+}
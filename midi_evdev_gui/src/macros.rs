@@ -0,0 +1,141 @@
+use crate::midi_utils::MidiCC;
+use color_eyre::eyre::{eyre, Result};
+use midi_types::status::{CONTROL_CHANGE, NOTE_OFF, NOTE_ON, PROGRAM_CHANGE};
+use serde::Deserialize;
+use std::time::Duration;
+
+/// A sequence of messages one button press sends, with waits in between.
+#[derive(Debug, Clone, Deserialize)]
+pub struct MacroConfig {
+    /// Name that mappings use as `macro = "name"`.
+    pub name: String,
+    pub steps: Vec<MacroStep>,
+}
+
+impl MacroConfig {
+    pub fn validate(&self) -> Result<()> {
+        for step in &self.steps {
+            step.validate().map_err(|e| eyre!("Macro {}: {e}", self.name))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MacroStep {
+    #[serde(flatten)]
+    pub action: MacroAction,
+    /// MIDI channel, 0-based; by default the mapping's.
+    pub channel: Option<u8>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MacroAction {
+    /// Wait this many milliseconds before the next step.
+    Wait(u64),
+    /// Controller, by number or name, and value.
+    Cc(MidiCC, u8),
+    /// Note on at full velocity.
+    Note(u8),
+    NoteOff(u8),
+    Program(u8),
+    /// 14-bit bank number, sent as bank select MSB and LSB.
+    Bank(u16),
+    /// Raw bytes of one message, SysEx included.
+    Midi(Vec<u8>),
+}
+
+impl MacroStep {
+    fn validate(&self) -> Result<()> {
+        if self.channel.is_some_and(|channel| channel > 15) {
+            return Err(eyre!("MIDI channel out of range 0-15"));
+        }
+        match &self.action {
+            MacroAction::Cc(_, value) if *value > 127 => Err(eyre!("controller value {value} out of range 0-127")),
+            MacroAction::Note(note) | MacroAction::NoteOff(note) if *note > 127 => {
+                Err(eyre!("note {note} out of range 0-127"))
+            }
+            MacroAction::Program(program) if *program > 127 => Err(eyre!("program {program} out of range 0-127")),
+            MacroAction::Bank(bank) if *bank > 0x3FFF => Err(eyre!("bank {bank} out of range 0-16383")),
+            MacroAction::Midi(bytes) => check_message(bytes),
+            _ => Ok(()),
+        }
+    }
+
+    /// How long to wait before the next step, for a wait.
+    pub const fn delay(&self) -> Option<Duration> {
+        match self.action {
+            MacroAction::Wait(ms) => Some(Duration::from_millis(ms)),
+            _ => None,
+        }
+    }
+
+    /// What the step sends, on its own channel or else `channel`.
+    pub fn messages(&self, channel: u8) -> Vec<Vec<u8>> {
+        let channel = self.channel.unwrap_or(channel);
+        match &self.action {
+            MacroAction::Wait(_) => Vec::new(),
+            MacroAction::Cc(control, value) => vec![vec![CONTROL_CHANGE | channel, *control as u8, *value]],
+            MacroAction::Note(note) => vec![vec![NOTE_ON | channel, *note, 0x7F]],
+            MacroAction::NoteOff(note) => vec![vec![NOTE_OFF | channel, *note, 0]],
+            MacroAction::Program(program) => vec![vec![PROGRAM_CHANGE | channel, *program]],
+            MacroAction::Bank(bank) => vec![
+                vec![CONTROL_CHANGE | channel, MidiCC::BankSelect as u8, u8::try_from(bank >> 7).unwrap_or_default()],
+                vec![CONTROL_CHANGE | channel, MidiCC::BankSelectLsb as u8, (bank & 0x7F) as u8],
+            ],
+            MacroAction::Midi(bytes) => vec![bytes.clone()],
+        }
+    }
+}
+
+/// Check raw bytes are a status byte followed by data bytes, SysEx's closing F7 aside.
+pub fn check_message(bytes: &[u8]) -> Result<()> {
+    let Some((&status, data)) = bytes.split_first().filter(|&(&status, _)| status >= 0x80) else {
+        return Err(eyre!("MIDI message must start with a status byte"));
+    };
+    let data = if status == 0xF0 { data.strip_suffix(&[0xF7]).unwrap_or(data) } else { data };
+    data.iter()
+        .find(|&&byte| byte > 0x7F)
+        .map_or(Ok(()), |byte| Err(eyre!("MIDI data byte {byte:#04X} out of range 0-127")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn step(action: MacroAction, channel: Option<u8>) -> MacroStep {
+        MacroStep { action, channel }
+    }
+
+    #[test]
+    fn validate_rejects_out_of_range_steps() {
+        for step in [
+            step(MacroAction::Note(60), Some(16)),
+            step(MacroAction::Cc(MidiCC::Volume, 128), None),
+            step(MacroAction::Bank(0x4000), None),
+            step(MacroAction::Midi(Vec::new()), None),
+            step(MacroAction::Midi(vec![60, 100]), None),
+            step(MacroAction::Midi(vec![0x90, 200, 1]), None),
+            step(MacroAction::Midi(vec![0xF0, 0x7D, 0x80, 0xF7]), None),
+        ] {
+            assert!(step.validate().is_err(), "{step:?}");
+        }
+        assert!(step(MacroAction::Bank(0x3FFF), Some(15)).validate().is_ok());
+        assert!(step(MacroAction::Midi(vec![0xF0, 0x7D, 0x01, 0xF7]), None).validate().is_ok());
+    }
+
+    #[test]
+    fn bank_is_split_into_msb_and_lsb() {
+        let messages = step(MacroAction::Bank(0x0123), None).messages(2);
+        assert_eq!(messages, [[0xB2, 0x00, 0x02], [0xB2, 0x20, 0x23]]);
+    }
+
+    #[test]
+    fn step_channel_overrides_the_mappings() {
+        assert_eq!(step(MacroAction::Note(60), None).messages(3), [[0x93, 60, 0x7F]]);
+        assert_eq!(step(MacroAction::Note(60), Some(9)).messages(3), [[0x99, 60, 0x7F]]);
+        assert_eq!(step(MacroAction::Program(5), Some(0)).messages(3), [[0xC0, 5]]);
+        assert!(step(MacroAction::Wait(10), None).messages(3).is_empty());
+    }
+}
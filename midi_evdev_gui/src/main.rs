@@ -1,94 +1,173 @@
+mod chord;
+mod engine;
+mod input;
+mod macros;
+mod mackie;
+mod midi_in;
 mod midi_utils;
-mod sdl_js;
-mod evdev_js;
-
-use evdev_rs::enums::EV_KEY;
-use evdev_rs::InputEvent;
-use midi_utils::MidiCC;
-use evdev_rs::enums::{EventCode, EventType, EV_ABS};
-use evdev_rs::Device;
-use evdev_rs::ReadFlag;
-use midir::{MidiOutput, MidiOutputConnection};
-use midi_types::{MidiMessage, Channel, Control, Value7, Note};
-use std::fs::File;
-use midi_convert::render_slice::MidiRenderSlice;
-use midi_types::status::{NOTE_OFF, NOTE_ON};
+mod mpe;
+mod notes;
+mod osc;
+mod osc_in;
+mod output;
+mod ports;
+mod profile;
+mod rtp_midi;
+mod scale;
+mod script;
+mod sink;
+mod smf;
+mod sysex;
+mod timer;
+mod transport;
+mod ump;
+mod virtual_axis;
+
+use clap::{Parser, Subcommand};
+use color_eyre::eyre::eyre;
+use engine::{Engine, Event};
+use input::InputDevice;
+use midi_in::MidiIn;
+use output::Destination;
+use ports::PortSelector;
+use profile::Profile;
+use midir::MidiOutput;
+use std::path::PathBuf;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread;
+
+/// Map evdev joystick events to MIDI.
+#[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Profile to load; without one, /dev/input/event27 is used
+    profile: Option<PathBuf>,
+    /// MIDI output port: exact name, unique substring or /regex/. Overrides the profile's first output
+    #[arg(long)]
+    port: Option<PortSelector>,
+    /// Publish a virtual MIDI port with this name instead of connecting to one
+    #[arg(long, conflicts_with = "port")]
+    virtual_port: Option<String>,
+    /// Print outgoing MIDI as hex instead of opening any ports
+    #[arg(long)]
+    dump: bool,
+    /// Record everything sent to this Standard MIDI File until exit or a `toggle_recording` button
+    #[arg(long, value_name = "FILE")]
+    record: Option<PathBuf>,
+    /// Accept OSC commands on this address, e.g. 127.0.0.1:9000
+    #[arg(long, value_name = "ADDRESS")]
+    osc_listen: Option<String>,
+}
 
+#[derive(Subcommand)]
+enum Command {
+    /// List the available MIDI output ports
+    ListPorts,
+}
 
+fn main() -> color_eyre::Result<()> {
+    color_eyre::install()?;
+    let cli = Cli::parse();
 
-const MAX_JOYSTICK_VALUE: f32 = 65535.0;
-const MIDI_MAX_VALUE: u8 = 127;
+    if matches!(cli.command, Some(Command::ListPorts)) {
+        let midi_out = MidiOutput::new(output::CLIENT_NAME).map_err(|e| eyre!("Failed to create MIDI output: {e}"))?;
+        for port in midi_out.ports() {
+            println!("{}", ports::port_name(&midi_out, &port));
+        }
+        return Ok(());
+    }
 
-fn main() {
-    let file = File::open("/dev/input/event27").expect("Failed to open device file");
-    let device = Device::new_from_file(file).expect("Failed to create device from file");
+    let profile = match &cli.profile {
+        Some(path) => Profile::load(path)?,
+        None => Profile::default(),
+    };
+    if profile.devices.is_empty() {
+        return Err(eyre!("Profile has no devices"));
+    }
+    let devices = profile.devices.iter().map(InputDevice::open).collect::<color_eyre::Result<Vec<_>>>()?;
 
-    let midi_out = MidiOutput::new("My MIDI Output").expect("Failed to create MIDI output");
-    let ports = midi_out.ports();
-    let out_port = ports.get(0).expect("No MIDI output ports available");
-    let port_name = midi_out.port_name(out_port).unwrap_or_else(|_| "Unknown port".to_string());
+    let destination = match (cli.port, cli.virtual_port) {
+        (Some(selector), _) => Some(Destination::Port(selector)),
+        (None, Some(name)) => Some(Destination::Virtual(name)),
+        (None, None) => None,
+    };
+    let mut engine = Engine::new(&profile, destination, cli.dump)?;
+    if let Some(path) = cli.record {
+        engine.start_recording(path);
+    }
 
-    let mut conn_out = midi_out.connect(out_port, "midir-test").expect("Failed to connect MIDI output");
+    // Every device gets its own reader thread; events from all of them, and any
+    // MIDI input, arrive here in the order they were read and drive the same engine.
+    let (tx, rx) = mpsc::channel();
+    input::spawn_readers(devices, &tx);
+    let _inputs = profile
+        .inputs
+        .iter()
+        .enumerate()
+        .map(|(index, input)| MidiIn::open(index, &input.destination()?, tx.clone()))
+        .collect::<color_eyre::Result<Vec<_>>>()?;
+    if let Some(address) = &cli.osc_listen {
+        osc_in::spawn_server(address, tx.clone())?;
+    }
+    forward_signals(tx)?;
 
+    // Whichever way we leave, no note is left sounding and the recording is saved.
+    let mut connected = profile.devices.len();
+    let mut profile_path = cli.profile;
     loop {
-        match device.next_event(ReadFlag::NORMAL) {
-            Ok((_, event)) => {
-                match event.event_type() {
-                    Some(EventType::EV_ABS) => process_abs_event(event, &mut conn_out, &port_name),
-                    Some(EventType::EV_KEY) => process_key_event(event, &mut conn_out, &port_name),
-                    _ => {}
+        // Wake up in time for the engine's next timer, such as a clock tick
+        match rx.recv_timeout(engine.timeout()) {
+            Ok(Event::Shutdown) | Err(RecvTimeoutError::Disconnected) => break,
+            Ok(Event::Reload) => {
+                let reloaded = profile_path.as_deref().map_or_else(|| Ok(Profile::default()), Profile::load);
+                if let Err(e) = reloaded.and_then(|profile| engine.load_mappings(&profile)) {
+                    eprintln!("Failed to reload profile: {e:?}");
+                }
+            }
+            // Switching profiles swaps mappings like a reload, and later reloads use the new file
+            Ok(Event::OscIn(event)) if event.message.addr == osc_in::PROFILE => {
+                match osc_in::arg_str(&event.message.args, 0).map(PathBuf::from) {
+                    Some(path) => match Profile::load(&path).and_then(|profile| engine.load_mappings(&profile)) {
+                        Ok(()) => profile_path = Some(path),
+                        Err(e) => eprintln!("Failed to switch profile: {e:?}"),
+                    },
+                    None => eprintln!("Ignoring OSC {}: expected a profile path", osc_in::PROFILE),
                 }
             }
-            Err(e) => {
-                eprintln!("Error reading event: {:?}", e);
+            Ok(event) => {
+                let disconnected = matches!(event, Event::Disconnected(_));
+                engine.handle(event);
+                if disconnected {
+                    connected -= 1;
+                    if connected == 0 {
+                        break;
+                    }
+                }
             }
+            Err(RecvTimeoutError::Timeout) => {}
         }
+        engine.poll();
     }
-}
-
-
-fn process_abs_event(event: evdev_rs::InputEvent, conn_out: &mut MidiOutputConnection, port_name: &str) {
-    let (control, value) = match event.event_code {
-        EventCode::EV_ABS(EV_ABS::ABS_X) => (MidiCC::Pan, map_value(event.value)),
-        EventCode::EV_ABS(EV_ABS::ABS_Y) => (MidiCC::Volume, map_value(event.value)),
-        EventCode::EV_ABS(EV_ABS::ABS_RX) => (MidiCC::ModulationWheel, map_value(event.value)),
-        EventCode::EV_ABS(EV_ABS::ABS_RY) => (MidiCC::Expression, map_value(event.value)),
-
-        _ => return,
-    };
 
-    let msg = MidiMessage::ControlChange(
-        Channel::new(0),
-        Control::new(control as u8),
-        Value7::new(value),
-    );
-    let mut buf = [0; 3];
-    let len = msg.render_slice(&mut buf);
-    conn_out.send(&buf[..len]).expect("Failed to send MIDI message");
-    println!("Axis moved: {} converted to MIDI {:?} on {}", event.value, value, port_name);
+    engine.release_all();
+    engine.stop_recording();
+    Ok(())
 }
 
-fn process_key_event(event: InputEvent, conn_out: &mut MidiOutputConnection, port_name: &str) {
-    let note = Note::new(60);
-
-    if let EventCode::EV_KEY(key_code) = event.event_code {
-        // Check if the key code matches the desired button (e.g., BTN_BASE6 or code 300)
-        if key_code == EV_KEY::BTN_BASE6 || key_code == EV_KEY::KEY_UNKNOWN {  // KEY_UNKNOWN as placeholder for custom key code
-            let velocity = if event.value == 1 { Value7::new(127) } else { Value7::new(0) }; // Velocity 127 for note on, 0 for note off
-
-            let msg = if event.value == 1 {
-                MidiMessage::NoteOn(Channel::new(0), note, velocity)
-            } else {
-                MidiMessage::NoteOff(Channel::new(0), note, velocity)
-            };
-
-            let mut buf = [0; 3];
-            let len = msg.render_slice(&mut buf);
-            conn_out.send(&buf[..len]).expect("Failed to send MIDI message");
-            println!("Button {:?}: {} converted to MIDI Note {:?} on {}", key_code, event.value, note, port_name);
+/// SIGINT and SIGTERM shut down cleanly, SIGHUP reloads the profile's mappings.
+fn forward_signals(tx: Sender<Event>) -> color_eyre::Result<()> {
+    let mut signals = Signals::new([SIGINT, SIGTERM, SIGHUP])?;
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            let event = if signal == SIGHUP { Event::Reload } else { Event::Shutdown };
+            if tx.send(event).is_err() {
+                return;
+            }
         }
-    }
-}
-fn map_value(value: i32) -> u8 {
-    (value as f32 / MAX_JOYSTICK_VALUE * MIDI_MAX_VALUE as f32) as u8
+    });
+    Ok(())
 }
@@ -1,26 +1,43 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
-use eframe::{App, CreationContext, egui};
+// Shared with midi-evdev, which sends what the GUI only displays
+#[allow(dead_code)]
+mod mackie;
+mod midi_utils;
+mod ports;
+
+use clap::Parser;
+use mackie::{Button, Led, MackieState};
+use midi_utils::MidiCC;
+use ports::PortSelector;
+use color_eyre::eyre::{eyre, Result};
+use eframe::{App, egui};
 use sdl2::event::Event;
 use sdl2::joystick::Joystick;
 use sdl2::JoystickSubsystem;
-use midir::{MidiOutput, MidiOutputConnection, MidiOutputPort};
-use midi_types::{MidiMessage, Channel, Control, Value7, Note};
-use std::fs::File;
-use egui::{Context, Pos2};
-use midi_convert::render_slice::MidiRenderSlice;
+use midir::{Ignore, MidiInput, MidiInputConnection, MidiOutput, MidiOutputPort};
+use rosc::{decoder, encoder, OscMessage, OscPacket, OscType};
+use std::net::UdpSocket;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::Duration;
+use egui::Pos2;
 use egui_graphs::{DefaultEdgeShape, DefaultNodeShape, Graph, GraphView};
 use petgraph::Directed;
-use petgraph::stable_graph::{StableGraph};
-use petgraph::visit::Walker;
-use rayon::iter::Positions;
+use petgraph::stable_graph::StableGraph;
 
-const MAX_JOYSTICK_VALUE: i32 = 32767;
+const MAX_JOYSTICK_VALUE: f32 = 32767.0;
+/// Buttons whose LEDs the Mackie Control window shows, besides the strips'.
+const MACKIE_LEDS: [&str; 8] = ["rewind", "fast_forward", "stop", "play", "record", "cycle", "click", "marker"];
+/// midi-evdev's OSC address for its state, and where the state has its key.
+const STATE_QUERY: &str = "/js-midi/query";
+const STATE_KEY: &str = "/js-midi/key";
+/// How often midi-evdev is asked for its state.
+const STATE_INTERVAL: Duration = Duration::from_millis(500);
 
 struct JoystickState {
     joystick: Joystick,
     axes_states: Vec<f32>,
     buttons_states: Vec<bool>,
-    position: Option<egui::Pos2>
     // Add other state fields as necessary
 }
 
@@ -33,24 +50,26 @@ impl JoystickState {
             joystick,
             axes_states: vec![0.0; num_axes],
             buttons_states: vec![false; num_buttons],
-            position: None,
         }
     }
 
     fn update_axis(&mut self, axis_idx: u8, value: i16) {
         if let Some(state) = self.axes_states.get_mut(axis_idx as usize) {
-            *state = value as f32; // Convert or scale value as needed
+            *state = f32::from(value); // Convert or scale value as needed
         }
     }
     // TODO Add more update methods for buttons, hats, etc.
 }
 
+// The graph only draws the nodes so far; nothing reads their payloads yet
+#[allow(dead_code)]
 #[derive(Clone)]
 enum JoystickNode {
     Axis(AxisNode),
     Button(ButtonNode),
 }
 
+#[allow(dead_code)]
 #[derive(Clone)]
 struct AxisNode {
     axis_index: usize,
@@ -58,6 +77,7 @@ struct AxisNode {
     position: Option<Pos2>,
 }
 
+#[allow(dead_code)]
 #[derive(Clone)]
 struct ButtonNode {
     button_index: usize,
@@ -65,55 +85,87 @@ struct ButtonNode {
     position: Option<Pos2>,
 }
 
-impl AxisNode {
-    fn new(axis_index: usize, value: f32) -> Self {
-        Self { axis_index, value, position: None }
-    }
-}
-
-impl ButtonNode {
-    fn new(button_index: usize, state: bool) -> Self {
-        Self { button_index, state, position: None }
-    }
-}
-
 struct MyApp {
-    sdl_context: sdl2::Sdl,
+    /// SDL shuts down when this is dropped.
+    _sdl_context: sdl2::Sdl,
     joysticks: Vec<JoystickState>,
     event_pump: sdl2::EventPump,
     midi_out: MidiOutput,
-    ports: Vec<midir::MidiOutputPort>,
     out_port: Option<MidiOutputPort>,
     port_name: Option<String>,
-    connection_graph: Graph<(JoystickNode), (), Directed>,
+    cc_search: String,
+    selected_cc: Option<MidiCC>,
+    /// What the DAW tells the Mackie Control surface, kept up to date by `_mackie_in`.
+    mackie: Option<Arc<Mutex<MackieState>>>,
+    _mackie_in: Option<MidiInputConnection<()>>,
+    /// The key midi-evdev's `degree` mappings play in, while it reports one.
+    key: Option<Arc<Mutex<Option<String>>>>,
+    connection_graph: Graph<JoystickNode, (), Directed>,
 }
 
-impl Default for MyApp {
-    fn default() -> Self {
-        let sdl_context = sdl2::init().unwrap();
-        let joystick_subsystem = sdl_context.joystick().unwrap();
-        let joysticks = open_virpil_joysticks(&joystick_subsystem);
-        let event_pump = sdl_context.event_pump().unwrap();
-
-        let midi_out = MidiOutput::new("My MIDI Output").expect("Failed to create MIDI output");
-        let ports = midi_out.ports();
-        let out_port = ports.get(0).cloned();
+/// Joystick to MIDI mapper GUI.
+#[derive(Parser)]
+struct Args {
+    /// MIDI output port to preselect: exact name, unique substring or /regex/
+    #[arg(long)]
+    port: Option<PortSelector>,
+    /// Port the DAW sends Mackie Control feedback on, to show faders, LEDs and displays
+    #[arg(long)]
+    mackie: Option<PortSelector>,
+    /// Address midi-evdev listens for OSC on (its --osc-listen), to show its current key
+    #[arg(long, value_name = "ADDRESS")]
+    engine: Option<String>,
+}
 
-        let port_name = out_port.as_ref()
-            .and_then(|port| midi_out.port_name(port).ok())
-            .or_else(|| Some("Unknown port".to_string()));
+impl MyApp {
+    fn new(port: Option<&PortSelector>, mackie: Option<&PortSelector>, engine: Option<&str>) -> Result<Self> {
+        let sdl_context = sdl2::init().map_err(|e| eyre!(e))?;
+        let joystick_subsystem = sdl_context.joystick().map_err(|e| eyre!(e))?;
+        let joysticks = open_virpil_joysticks(&joystick_subsystem)?;
+        let event_pump = sdl_context.event_pump().map_err(|e| eyre!(e))?;
+
+        let midi_out = MidiOutput::new("My MIDI Output")?;
+        // Nothing is preselected unless asked for; the port list window is where to pick one.
+        let (out_port, port_name) = match port.map(|selector| ports::find_port(&midi_out, selector)) {
+            Some(Ok((port, name))) => (Some(port), Some(name)),
+            Some(Err(e)) => {
+                eprintln!("{e}");
+                (None, None)
+            }
+            None => (None, None),
+        };
         let connection_graph = generate_graph(&joysticks, &[], &[]);
+        let (mackie, mackie_in) = match mackie.map(open_mackie_feedback) {
+            Some(Ok((state, connection))) => (Some(state), Some(connection)),
+            Some(Err(e)) => {
+                eprintln!("{e}");
+                (None, None)
+            }
+            None => (None, None),
+        };
+        let key = match engine.map(watch_engine_key) {
+            Some(Ok(key)) => Some(key),
+            Some(Err(e)) => {
+                eprintln!("{e}");
+                None
+            }
+            None => None,
+        };
 
-        Self {
-            sdl_context,
+        Ok(Self {
+            _sdl_context: sdl_context,
             joysticks,
             event_pump,
             midi_out,
-            ports,
             out_port,
             port_name,
+            cc_search: String::new(),
+            selected_cc: None,
+            mackie,
+            _mackie_in: mackie_in,
+            key,
             connection_graph,
-        }
+        })
     }
 }
 
@@ -147,7 +199,7 @@ fn generate_graph(
 ) -> Graph<JoystickNode, ()> {
     let mut g = StableGraph::new();
 
-    for (joystick, (axes_pos, buttons_pos)) in joysticks.iter().zip(axes_positions.iter().zip(buttons_positions.iter())) {
+    for (joystick, (_axes_pos, _buttons_pos)) in joysticks.iter().zip(axes_positions.iter().zip(buttons_positions.iter())) {
         //let nodes = joystick_to_nodes(joystick, axes_pos, buttons_pos);
         // Set static positions for now
         let positions = vec![Some(egui::Pos2::new(100.0, 100.0)); joystick.axes_states.len() + joystick.buttons_states.len()];
@@ -178,6 +230,7 @@ fn create_node_with_position(node_type: NodeType, index: usize, state: f32, posi
 }
 
 // Enum to distinguish between node types
+#[derive(Clone, Copy)]
 enum NodeType {
     Axis,
     Button,
@@ -189,20 +242,93 @@ fn joystick_to_nodes(
     buttons_positions: &[Option<egui::Pos2>],
 ) -> Vec<JoystickNode> {
     let mut nodes = Vec::new();
-    for (axis_idx, (&state, &position)) in joystick.axes_states.iter().zip(axes_positions.iter()).enumerate() {
+    for (axis_idx, (&state, &_position)) in joystick.axes_states.iter().zip(axes_positions.iter()).enumerate() {
         //nodes.push(create_node_with_position(NodeType::Axis, axis_idx, state, position));
         nodes.push(create_node_with_position(NodeType::Axis, axis_idx, state, Some(egui::Pos2::new(100.0, 100.0))));
     }
-    for (button_idx, (&state, &position)) in joystick.buttons_states.iter().zip(buttons_positions.iter()).enumerate() {
+    for (button_idx, (&state, &_position)) in joystick.buttons_states.iter().zip(buttons_positions.iter()).enumerate() {
         //nodes.push(create_node_with_position(NodeType::Button, button_idx, if state { 1.0 } else { 0.0 }, position));
         nodes.push(create_node_with_position(NodeType::Button, button_idx, if state { 1.0 } else { 0.0 }, Some(egui::Pos2::new(100.0, 100.0))));
     }
     nodes
 }
 
-fn open_virpil_joysticks(p0: &JoystickSubsystem) -> Vec<JoystickState> {
+/// Listen to the DAW's Mackie Control feedback on `selector`, without answering it;
+/// the surface itself is `midi-evdev` with a `mackie` output.
+fn open_mackie_feedback(
+    selector: &PortSelector,
+) -> Result<(Arc<Mutex<MackieState>>, MidiInputConnection<()>)> {
+    let mut midi_in = MidiInput::new("Mackie Control feedback")?;
+    midi_in.ignore(Ignore::None);
+    let (port, name) = ports::find_port(&midi_in, selector)?;
+    let state = Arc::new(Mutex::new(MackieState::default()));
+    let shared = Arc::clone(&state);
+    let connection = midi_in
+        .connect(
+            &port,
+            "mackie-feedback",
+            move |_stamp, message, (): &mut ()| {
+                shared.lock().unwrap_or_else(PoisonError::into_inner).receive(message);
+            },
+            (),
+        )
+        .map_err(|e| eyre!("Failed to connect MIDI input {name}: {e}"))?;
+    Ok((state, connection))
+}
+
+/// Ask midi-evdev's OSC server at `address` for its state every `STATE_INTERVAL`,
+/// keeping the key it reports.
+fn watch_engine_key(address: &str) -> Result<Arc<Mutex<Option<String>>>> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket
+        .connect(address)
+        .map_err(|e| eyre!("Failed to reach midi-evdev at {address}: {e}"))?;
+    socket.set_read_timeout(Some(STATE_INTERVAL))?;
+    let query = encoder::encode(&OscPacket::Message(OscMessage {
+        addr: STATE_QUERY.to_string(),
+        args: Vec::new(),
+    }))?;
+
+    let key = Arc::new(Mutex::new(None));
+    let shared = Arc::clone(&key);
+    thread::spawn(move || {
+        let mut buf = [0; decoder::MTU];
+        loop {
+            thread::sleep(STATE_INTERVAL);
+            // Nothing comes back while midi-evdev is not running; try again later
+            let Ok(len) = socket.send(&query).and_then(|_| socket.recv(&mut buf)) else {
+                continue;
+            };
+            let Ok((_, OscPacket::Bundle(bundle))) = decoder::decode_udp(&buf[..len]) else {
+                continue;
+            };
+            let reported = bundle.content.iter().find_map(|packet| match packet {
+                OscPacket::Message(message) if message.addr == STATE_KEY => match message.args.first() {
+                    Some(OscType::String(name)) => Some(name.clone()),
+                    _ => None,
+                },
+                _ => None,
+            });
+            *shared.lock().unwrap_or_else(PoisonError::into_inner) = reported;
+        }
+    });
+    Ok(key)
+}
+
+/// A button's LED, as a coloured label.
+fn mackie_led(ui: &mut egui::Ui, state: &MackieState, button: Button) {
+    let lit = match state.leds[usize::from(button.0)] {
+        Led::Off => false,
+        Led::On => true,
+        // Blink at 2 Hz
+        Led::Flashing => ui.input(|input| input.time).fract() < 0.5,
+    };
+    ui.colored_label(if lit { egui::Color32::RED } else { egui::Color32::DARK_GRAY }, button.name());
+}
+
+fn open_virpil_joysticks(p0: &JoystickSubsystem) -> Result<Vec<JoystickState>> {
     let mut joystick_states = Vec::new();
-    for id in 0..p0.num_joysticks().unwrap() {
+    for id in 0..p0.num_joysticks().map_err(|e| eyre!(e))? {
         if let Ok(name) = p0.name_for_index(id) {
             if name.contains("VIRPIL") {
                 if let Ok(joystick) = p0.open(id) {
@@ -211,7 +337,99 @@ fn open_virpil_joysticks(p0: &JoystickSubsystem) -> Vec<JoystickState> {
             }
         }
     }
-    joystick_states
+    Ok(joystick_states)
+}
+
+impl MyApp {
+    /// A panel that shows all MIDI ports.
+    fn ports_window(&mut self, ctx: &egui::Context) {
+        egui::Window::new("MIDI Ports").show(ctx, |ui| {
+            match self.midi_out.ports().as_slice() {
+                [] => {
+                    ui.label("No MIDI output ports available.");
+                },
+                ports => {
+                    for port in ports {
+                        let port_name = ports::port_name(&self.midi_out, port);
+                        if ui.selectable_label(self.out_port.as_ref() == Some(port), &port_name).clicked() {
+                            self.out_port = Some(port.clone());
+                            self.port_name = Some(port_name);
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    /// Searchable list of every controller, by number or name.
+    fn controllers_window(&mut self, ctx: &egui::Context) {
+        egui::Window::new("MIDI Controllers").show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                ui.label("Search:");
+                ui.text_edit_singleline(&mut self.cc_search);
+            });
+            if let Some(cc) = self.selected_cc {
+                ui.label(format!("Selected: CC {} {} ({:?})", cc as u8, cc.label(), cc.category()));
+            }
+            egui::ScrollArea::vertical().max_height(300.0).show(ui, |ui| {
+                for cc in MidiCC::ALL.into_iter().filter(|cc| cc.matches(&self.cc_search)) {
+                    let text = format!("{:3} {} ({:?})", cc as u8, cc.label(), cc.category());
+                    if ui.selectable_label(self.selected_cc == Some(cc), text).clicked() {
+                        self.selected_cc = Some(cc);
+                    }
+                }
+            });
+        });
+    }
+
+    /// The DAW's side of the Mackie Control surface: faders, LEDs and displays.
+    fn mackie_window(&self, ctx: &egui::Context) {
+        if let Some(mackie) = &self.mackie {
+            let state = mackie.lock().unwrap_or_else(PoisonError::into_inner).clone();
+            egui::Window::new("Mackie Control").show(ctx, |ui| {
+                ui.label(if state.connected { "DAW connected" } else { "Waiting for the DAW" });
+                ui.monospace(String::from_utf8_lossy(&state.timecode).into_owned());
+                ui.monospace(state.lcd_line(0));
+                ui.monospace(state.lcd_line(1));
+                ui.horizontal(|ui| {
+                    for (fader, &position) in state.faders.iter().enumerate() {
+                        ui.vertical(|ui| {
+                            let is_master = fader + 1 == mackie::FADERS;
+                            let label = if is_master { "Master".to_string() } else { format!("{}", fader + 1) };
+                            let mut position = f32::from(position);
+                            let slider = egui::Slider::new(&mut position, 0.0..=16383.0).vertical();
+                            ui.add_enabled(false, slider.show_value(false));
+                            ui.label(label);
+                            if !is_master {
+                                for group in ["rec", "solo", "mute", "select"] {
+                                    if let Ok(button) = Button::try_from(format!("{group}_{}", fader + 1)) {
+                                        mackie_led(ui, &state, button);
+                                    }
+                                }
+                            }
+                        });
+                    }
+                });
+                ui.horizontal(|ui| {
+                    for name in MACKIE_LEDS {
+                        if let Ok(button) = Button::try_from(name.to_string()) {
+                            mackie_led(ui, &state, button);
+                        }
+                    }
+                });
+            });
+        }
+    }
+
+    /// The key midi-evdev is playing scale degrees in, transpositions included.
+    fn key_window(&self, ctx: &egui::Context) {
+        if let Some(key) = &self.key {
+            let key = key.lock().unwrap_or_else(PoisonError::into_inner).clone();
+            egui::Window::new("Key").show(ctx, |ui| {
+                ui.heading(key.as_deref().unwrap_or("No key"));
+            });
+        }
+    }
 }
 
 impl App for MyApp {
@@ -268,14 +486,14 @@ impl App for MyApp {
                     let mut joystick_buttons_positions = Vec::new();
 
                     ui.horizontal(|ui| {
-                        egui::Window::new(&joystick.joystick.name()).show(ui.ctx(), |ui| {
+                        egui::Window::new(joystick.joystick.name()).show(ui.ctx(), |ui| {
                             ui.label(format!("Number of axes: {}", joystick.joystick.num_axes()));
                             ui.label(format!("Number of buttons: {}", joystick.joystick.num_buttons()));
 
                             // Slider for each axis
                             ui.vertical(|ui| {
                                 for (axis_idx, state) in joystick.axes_states.iter().enumerate() {
-                                    let slider_response = ui.add(egui::Slider::new(&mut state.clone(), 0.0..=MAX_JOYSTICK_VALUE as f32).text(format!("Axis {}", axis_idx)));
+                                    let slider_response = ui.add(egui::Slider::new(&mut state.clone(), 0.0..=MAX_JOYSTICK_VALUE).text(format!("Axis {axis_idx}")));
                                     let slider_center = slider_response.rect.center();
                                     joystick_axes_positions.push(Some(slider_center));
                                 }
@@ -283,7 +501,7 @@ impl App for MyApp {
 
                             egui::Grid::new("button_grid").striped(true).show(ui, |ui| {
                                 for (button_idx, state) in joystick.buttons_states.iter().enumerate() {
-                                    let button_response = ui.checkbox(&mut state.clone(), format!("Button {}", button_idx));
+                                    let button_response = ui.checkbox(&mut state.clone(), format!("Button {button_idx}"));
                                     let button_center = button_response.rect.center();
                                     if (button_idx + 1) % 5 == 0 {
                                         ui.end_row();
@@ -320,22 +538,10 @@ impl App for MyApp {
                     // Add a button to save mappings to a file
                     // Add a button to load mappings from a file
 
-            // A panel that shows all MIDI ports
-            egui::Window::new("MIDI Ports").show(ctx, |ui| {
-                match self.midi_out.ports().as_slice() {
-                    [] => {
-                        ui.label("No MIDI output ports available.");
-                    },
-                    ports => {
-                        for port in ports {
-                            let port_name = self.midi_out.port_name(port).unwrap_or_else(|_| "Unknown port".to_string());
-                            if ui.selectable_label(self.out_port.as_ref() == Some(port), &port_name).clicked() {
-                                self.out_port = Some(port.clone());
-                            }
-                        }
-                    }
-                }
-            });
+            self.ports_window(ctx);
+            self.controllers_window(ctx);
+            self.mackie_window(ctx);
+            self.key_window(ctx);
             // Add the graph
             self.connection_graph = generate_graph(&self.joysticks, &axes_positions_all_joysticks, &buttons_positions_all_joysticks);
             // Try rendering as background layer
@@ -363,7 +569,10 @@ impl App for MyApp {
     }
 }
 
-fn main() -> Result<(), eframe::Error> {
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let app = MyApp::new(args.port.as_ref(), args.mackie.as_ref(), args.engine.as_deref())?;
+
     let viewport_options = egui::ViewportBuilder {
         inner_size: Some(egui::Vec2::new(1440.0, 1440.0)), // Set your desired window size
         resizable: Some(true), // Optional: Set whether the window is resizable
@@ -377,9 +586,6 @@ fn main() -> Result<(), eframe::Error> {
         ..Default::default()
     };
 
-    eframe::run_native(
-        "Joystick to MIDI Mapper",
-        native_options,
-        Box::new(|_cc| Box::new(MyApp::default())),
-    )
+    eframe::run_native("Joystick to MIDI Mapper", native_options, Box::new(move |_cc| Box::new(app)))
+        .map_err(|e| eyre!("{e}"))
 }
@@ -0,0 +1,60 @@
+use crate::output::{Destination, CLIENT_NAME};
+use crate::ports;
+use color_eyre::eyre::{eyre, Result};
+use midir::os::unix::VirtualInput;
+use midir::{Ignore, MidiInput, MidiInputConnection};
+use std::sync::mpsc::Sender;
+
+/// A complete MIDI message received on an input port.
+#[derive(Debug, Clone)]
+pub struct MidiInEvent {
+    /// Index of the profile input it arrived on.
+    pub input: usize,
+    pub message: Vec<u8>,
+}
+
+/// An open MIDI input. Messages are forwarded to the engine's queue until it is dropped.
+pub struct MidiIn {
+    _connection: MidiInputConnection<()>,
+}
+
+impl MidiIn {
+    pub fn open<T>(input: usize, destination: &Destination, tx: Sender<T>) -> Result<Self>
+    where
+        T: From<MidiInEvent> + Send + 'static,
+    {
+        let mut midi_in = MidiInput::new(CLIENT_NAME).map_err(|e| eyre!("Failed to create MIDI input: {e}"))?;
+        midi_in.ignore(Ignore::None);
+
+        let forward = move |_stamp, message: &[u8], (): &mut ()| {
+            let _ = tx.send(
+                MidiInEvent {
+                    input,
+                    message: message.to_vec(),
+                }
+                .into(),
+            );
+        };
+
+        let (name, connection) = match destination {
+            Destination::Virtual(name) => {
+                let connection = midi_in
+                    .create_virtual(name, forward, ())
+                    .map_err(|e| eyre!("Failed to create virtual MIDI input '{name}': {e}"))?;
+                (name.clone(), connection)
+            }
+            Destination::Port(selector) => {
+                let (port, name) = ports::find_port(&midi_in, selector)?;
+                let connection = midi_in
+                    .connect(&port, "midir-test", forward, ())
+                    .map_err(|e| eyre!("Failed to connect MIDI input {name}: {e}"))?;
+                (name, connection)
+            }
+        };
+        println!("Listening for MIDI on {name}");
+
+        Ok(Self {
+            _connection: connection,
+        })
+    }
+}
@@ -1,25 +1,325 @@
-use midi_types::{Control};
-
-#[derive(Copy, Clone)]
-pub enum MidiCC {
-    ModulationWheel = 1,
-    BreathController = 2,
-    FootController = 4,
-    PortamentoTime = 5,
-    Volume = 7,
-    Balance = 8,
-    Pan = 10,
-    Expression = 11,
-    SustainPedal = 64,
-    Portamento = 65,
-    ReverbLevel = 91,
-    ChorusLevel = 93,
-    ResetAllControllers = 121,
-    // ... add other controls as needed
-}
-
-impl Into<Control> for MidiCC {
-    fn into(self) -> Control {
-        Control::new(self as u8)
+use midi_types::Control;
+use serde::{de, Deserialize, Deserializer};
+use std::fmt;
+use std::str::FromStr;
+
+/// What kind of value a controller carries.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CcCategory {
+    /// 0-127, or the MSB of a 14-bit pair.
+    Continuous,
+    /// On at 64 and above, off below.
+    Switch,
+    /// Low byte of one of the 0-31 controllers.
+    Lsb,
+    /// Channel mode messages (120-127).
+    ChannelMode,
+    /// Not assigned by the MIDI specification.
+    Undefined,
+}
+
+macro_rules! controllers {
+    ($($name:ident = $number:literal, $label:literal, $category:ident;)*) => {
+        /// Every MIDI 1.0 control change number, with its standard name.
+        #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+        #[repr(u8)]
+        pub enum MidiCC {
+            $($name = $number,)*
+        }
+
+        impl MidiCC {
+            /// All controllers, indexed by number.
+            pub const ALL: [Self; 128] = [$(Self::$name,)*];
+
+            /// Identifier used in profiles, e.g. `FilterCutoff`.
+            pub const fn name(self) -> &'static str {
+                match self {
+                    $(Self::$name => stringify!($name),)*
+                }
+            }
+
+            /// Name as the MIDI specification puts it.
+            pub const fn label(self) -> &'static str {
+                match self {
+                    $(Self::$name => $label,)*
+                }
+            }
+
+            pub const fn category(self) -> CcCategory {
+                match self {
+                    $(Self::$name => CcCategory::$category,)*
+                }
+            }
+        }
+    };
+}
+
+controllers! {
+    BankSelect = 0, "Bank Select", Continuous;
+    ModulationWheel = 1, "Modulation Wheel", Continuous;
+    BreathController = 2, "Breath Controller", Continuous;
+    Undefined3 = 3, "Undefined", Undefined;
+    FootController = 4, "Foot Controller", Continuous;
+    PortamentoTime = 5, "Portamento Time", Continuous;
+    DataEntry = 6, "Data Entry", Continuous;
+    Volume = 7, "Channel Volume", Continuous;
+    Balance = 8, "Balance", Continuous;
+    Undefined9 = 9, "Undefined", Undefined;
+    Pan = 10, "Pan", Continuous;
+    Expression = 11, "Expression", Continuous;
+    EffectControl1 = 12, "Effect Control 1", Continuous;
+    EffectControl2 = 13, "Effect Control 2", Continuous;
+    Undefined14 = 14, "Undefined", Undefined;
+    Undefined15 = 15, "Undefined", Undefined;
+    GeneralPurpose1 = 16, "General Purpose 1", Continuous;
+    GeneralPurpose2 = 17, "General Purpose 2", Continuous;
+    GeneralPurpose3 = 18, "General Purpose 3", Continuous;
+    GeneralPurpose4 = 19, "General Purpose 4", Continuous;
+    Undefined20 = 20, "Undefined", Undefined;
+    Undefined21 = 21, "Undefined", Undefined;
+    Undefined22 = 22, "Undefined", Undefined;
+    Undefined23 = 23, "Undefined", Undefined;
+    Undefined24 = 24, "Undefined", Undefined;
+    Undefined25 = 25, "Undefined", Undefined;
+    Undefined26 = 26, "Undefined", Undefined;
+    Undefined27 = 27, "Undefined", Undefined;
+    Undefined28 = 28, "Undefined", Undefined;
+    Undefined29 = 29, "Undefined", Undefined;
+    Undefined30 = 30, "Undefined", Undefined;
+    Undefined31 = 31, "Undefined", Undefined;
+    BankSelectLsb = 32, "Bank Select LSB", Lsb;
+    ModulationWheelLsb = 33, "Modulation Wheel LSB", Lsb;
+    BreathControllerLsb = 34, "Breath Controller LSB", Lsb;
+    Undefined3Lsb = 35, "Undefined LSB", Lsb;
+    FootControllerLsb = 36, "Foot Controller LSB", Lsb;
+    PortamentoTimeLsb = 37, "Portamento Time LSB", Lsb;
+    DataEntryLsb = 38, "Data Entry LSB", Lsb;
+    VolumeLsb = 39, "Channel Volume LSB", Lsb;
+    BalanceLsb = 40, "Balance LSB", Lsb;
+    Undefined9Lsb = 41, "Undefined LSB", Lsb;
+    PanLsb = 42, "Pan LSB", Lsb;
+    ExpressionLsb = 43, "Expression LSB", Lsb;
+    EffectControl1Lsb = 44, "Effect Control 1 LSB", Lsb;
+    EffectControl2Lsb = 45, "Effect Control 2 LSB", Lsb;
+    Undefined14Lsb = 46, "Undefined LSB", Lsb;
+    Undefined15Lsb = 47, "Undefined LSB", Lsb;
+    GeneralPurpose1Lsb = 48, "General Purpose 1 LSB", Lsb;
+    GeneralPurpose2Lsb = 49, "General Purpose 2 LSB", Lsb;
+    GeneralPurpose3Lsb = 50, "General Purpose 3 LSB", Lsb;
+    GeneralPurpose4Lsb = 51, "General Purpose 4 LSB", Lsb;
+    Undefined20Lsb = 52, "Undefined LSB", Lsb;
+    Undefined21Lsb = 53, "Undefined LSB", Lsb;
+    Undefined22Lsb = 54, "Undefined LSB", Lsb;
+    Undefined23Lsb = 55, "Undefined LSB", Lsb;
+    Undefined24Lsb = 56, "Undefined LSB", Lsb;
+    Undefined25Lsb = 57, "Undefined LSB", Lsb;
+    Undefined26Lsb = 58, "Undefined LSB", Lsb;
+    Undefined27Lsb = 59, "Undefined LSB", Lsb;
+    Undefined28Lsb = 60, "Undefined LSB", Lsb;
+    Undefined29Lsb = 61, "Undefined LSB", Lsb;
+    Undefined30Lsb = 62, "Undefined LSB", Lsb;
+    Undefined31Lsb = 63, "Undefined LSB", Lsb;
+    SustainPedal = 64, "Damper Pedal (Sustain)", Switch;
+    Portamento = 65, "Portamento On/Off", Switch;
+    Sostenuto = 66, "Sostenuto", Switch;
+    SoftPedal = 67, "Soft Pedal", Switch;
+    LegatoFootswitch = 68, "Legato Footswitch", Switch;
+    Hold2 = 69, "Hold 2", Switch;
+    SoundVariation = 70, "Sound Controller 1 (Sound Variation)", Continuous;
+    Resonance = 71, "Sound Controller 2 (Timbre/Harmonic Intensity)", Continuous;
+    ReleaseTime = 72, "Sound Controller 3 (Release Time)", Continuous;
+    AttackTime = 73, "Sound Controller 4 (Attack Time)", Continuous;
+    FilterCutoff = 74, "Sound Controller 5 (Brightness)", Continuous;
+    DecayTime = 75, "Sound Controller 6 (Decay Time)", Continuous;
+    VibratoRate = 76, "Sound Controller 7 (Vibrato Rate)", Continuous;
+    VibratoDepth = 77, "Sound Controller 8 (Vibrato Depth)", Continuous;
+    VibratoDelay = 78, "Sound Controller 9 (Vibrato Delay)", Continuous;
+    SoundController10 = 79, "Sound Controller 10", Continuous;
+    GeneralPurpose5 = 80, "General Purpose 5", Continuous;
+    GeneralPurpose6 = 81, "General Purpose 6", Continuous;
+    GeneralPurpose7 = 82, "General Purpose 7", Continuous;
+    GeneralPurpose8 = 83, "General Purpose 8", Continuous;
+    PortamentoControl = 84, "Portamento Control", Continuous;
+    Undefined85 = 85, "Undefined", Undefined;
+    Undefined86 = 86, "Undefined", Undefined;
+    Undefined87 = 87, "Undefined", Undefined;
+    HighResolutionVelocityPrefix = 88, "High Resolution Velocity Prefix", Continuous;
+    Undefined89 = 89, "Undefined", Undefined;
+    Undefined90 = 90, "Undefined", Undefined;
+    ReverbLevel = 91, "Effects 1 Depth (Reverb)", Continuous;
+    TremoloLevel = 92, "Effects 2 Depth (Tremolo)", Continuous;
+    ChorusLevel = 93, "Effects 3 Depth (Chorus)", Continuous;
+    DetuneLevel = 94, "Effects 4 Depth (Celeste/Detune)", Continuous;
+    PhaserLevel = 95, "Effects 5 Depth (Phaser)", Continuous;
+    DataIncrement = 96, "Data Increment", Continuous;
+    DataDecrement = 97, "Data Decrement", Continuous;
+    NrpnLsb = 98, "Non-Registered Parameter Number LSB", Continuous;
+    NrpnMsb = 99, "Non-Registered Parameter Number MSB", Continuous;
+    RpnLsb = 100, "Registered Parameter Number LSB", Continuous;
+    RpnMsb = 101, "Registered Parameter Number MSB", Continuous;
+    Undefined102 = 102, "Undefined", Undefined;
+    Undefined103 = 103, "Undefined", Undefined;
+    Undefined104 = 104, "Undefined", Undefined;
+    Undefined105 = 105, "Undefined", Undefined;
+    Undefined106 = 106, "Undefined", Undefined;
+    Undefined107 = 107, "Undefined", Undefined;
+    Undefined108 = 108, "Undefined", Undefined;
+    Undefined109 = 109, "Undefined", Undefined;
+    Undefined110 = 110, "Undefined", Undefined;
+    Undefined111 = 111, "Undefined", Undefined;
+    Undefined112 = 112, "Undefined", Undefined;
+    Undefined113 = 113, "Undefined", Undefined;
+    Undefined114 = 114, "Undefined", Undefined;
+    Undefined115 = 115, "Undefined", Undefined;
+    Undefined116 = 116, "Undefined", Undefined;
+    Undefined117 = 117, "Undefined", Undefined;
+    Undefined118 = 118, "Undefined", Undefined;
+    Undefined119 = 119, "Undefined", Undefined;
+    AllSoundOff = 120, "All Sound Off", ChannelMode;
+    ResetAllControllers = 121, "Reset All Controllers", ChannelMode;
+    LocalControl = 122, "Local Control On/Off", ChannelMode;
+    AllNotesOff = 123, "All Notes Off", ChannelMode;
+    OmniOff = 124, "Omni Mode Off", ChannelMode;
+    OmniOn = 125, "Omni Mode On", ChannelMode;
+    MonoOn = 126, "Mono Mode On", ChannelMode;
+    PolyOn = 127, "Poly Mode On", ChannelMode;
+}
+
+impl MidiCC {
+    /// Whether `query` picks this controller out in a search: by number, or by
+    /// a case-insensitive piece of its name or label.
+    pub fn matches(self, query: &str) -> bool {
+        let query = query.trim().to_lowercase();
+        query.is_empty()
+            || query.parse::<u8>().is_ok_and(|number| number == self as u8)
+            || self.name().to_lowercase().contains(&query)
+            || self.label().to_lowercase().contains(&query)
+    }
+}
+
+impl From<MidiCC> for Control {
+    fn from(cc: MidiCC) -> Self {
+        Self::new(cc as u8)
+    }
+}
+
+impl From<MidiCC> for u8 {
+    fn from(cc: MidiCC) -> Self {
+        cc as Self
+    }
+}
+
+impl TryFrom<u8> for MidiCC {
+    type Error = UnknownController;
+
+    fn try_from(number: u8) -> Result<Self, Self::Error> {
+        Self::ALL
+            .get(usize::from(number))
+            .copied()
+            .ok_or_else(|| UnknownController(number.to_string()))
+    }
+}
+
+impl FromStr for MidiCC {
+    type Err = UnknownController;
+
+    /// Parse a controller number (`74`) or name (`FilterCutoff`, any case).
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Ok(number) = s.parse::<u8>() {
+            return Self::try_from(number);
+        }
+        Self::ALL
+            .into_iter()
+            .find(|cc| cc.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| UnknownController(s.to_string()))
+    }
+}
+
+impl fmt::Display for MidiCC {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", *self as u8, self.name())
+    }
+}
+
+/// Profiles can give a controller as a number or by name.
+impl<'de> Deserialize<'de> for MidiCC {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Number(u8),
+            Name(String),
+        }
+
+        match Raw::deserialize(deserializer)? {
+            Raw::Number(number) => Self::try_from(number).map_err(de::Error::custom),
+            Raw::Name(name) => name.parse().map_err(de::Error::custom),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct UnknownController(String);
+
+impl fmt::Display for UnknownController {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown MIDI controller '{}'", self.0)
+    }
+}
+
+impl std::error::Error for UnknownController {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Deserialize)]
+    struct Mapping {
+        cc: MidiCC,
+    }
+
+    fn parse_toml(text: &str) -> Result<MidiCC, toml::de::Error> {
+        toml::from_str::<Mapping>(text).map(|mapping| mapping.cc)
+    }
+
+    #[test]
+    fn from_str_takes_numbers_and_names_in_any_case() {
+        assert_eq!("74".parse::<MidiCC>().ok(), Some(MidiCC::FilterCutoff));
+        assert_eq!(" 0 ".parse::<MidiCC>().ok(), Some(MidiCC::BankSelect));
+        assert_eq!("filtercutoff".parse::<MidiCC>().ok(), Some(MidiCC::FilterCutoff));
+        assert_eq!("ModulationWheel".parse::<MidiCC>().ok(), Some(MidiCC::ModulationWheel));
+    }
+
+    #[test]
+    fn from_str_rejects_unknown_controllers() {
+        let error = "128".parse::<MidiCC>().err().map(|e| e.to_string());
+        assert_eq!(error.as_deref(), Some("unknown MIDI controller '128'"));
+        assert!("Brightness".parse::<MidiCC>().is_err());
+        assert!("".parse::<MidiCC>().is_err());
+    }
+
+    #[test]
+    fn converts_to_its_control_number() {
+        assert_eq!(Control::from(MidiCC::Pan), Control::new(10));
+        assert_eq!(u8::from(MidiCC::PolyOn), 127);
+        assert_eq!(MidiCC::try_from(64).ok(), Some(MidiCC::SustainPedal));
+    }
+
+    #[test]
+    fn every_number_round_trips() {
+        for number in 0..=127 {
+            let cc = MidiCC::try_from(number).expect("every controller number is known");
+            assert_eq!(cc as u8, number, "{cc:?}");
+        }
+    }
+
+    #[test]
+    fn deserializes_from_number_or_name() {
+        assert_eq!(parse_toml("cc = 11").ok(), Some(MidiCC::Expression));
+        assert_eq!(parse_toml("cc = \"volume\"").ok(), Some(MidiCC::Volume));
+        assert!(parse_toml("cc = 200").is_err());
+        assert!(parse_toml("cc = \"Nope\"").is_err());
+        assert!(parse_toml("cc = -1").is_err());
     }
 }
@@ -0,0 +1,143 @@
+use crate::profile::{MpeConfig, MpeExpression, MpeScope, MpeZoneKind};
+use crate::ump::Voice;
+use midi_types::status::CONTROL_CHANGE;
+use std::collections::{BTreeMap, VecDeque};
+
+/// MPE's timbre controller.
+const TIMBRE: u8 = 74;
+const CENTRE: u32 = 0x8000_0000;
+
+/// An MPE zone on one output: a master channel for zone-wide messages and
+/// member channels handed out one per sounding note, so that pitch bend,
+/// timbre and pressure apply to that note alone.
+#[derive(Debug)]
+pub struct MpeZone {
+    master: u8,
+    members: u8,
+    bend_range: u8,
+    /// Member channels with no note, least recently used first.
+    free: VecDeque<u8>,
+    /// Member channels with a note, oldest first, and the device playing it.
+    busy: VecDeque<(u8, usize)>,
+    /// Last expression value each device sent, to start its new notes with, and
+    /// under `None` the last one sent to the whole zone.
+    expression: BTreeMap<(Option<usize>, MpeExpression), u32>,
+}
+
+impl MpeZone {
+    pub fn new(config: &MpeConfig) -> Self {
+        let members = config.members.clamp(1, 15);
+        let free = match config.zone {
+            MpeZoneKind::Lower => (1..=members).collect(),
+            MpeZoneKind::Upper => (15 - members..15).rev().collect(),
+        };
+        Self {
+            master: match config.zone {
+                MpeZoneKind::Lower => 0,
+                MpeZoneKind::Upper => 15,
+            },
+            members,
+            bend_range: config.bend_range,
+            free,
+            busy: VecDeque::new(),
+            expression: BTreeMap::new(),
+        }
+    }
+
+    /// The MPE Configuration Message (RPN 6) on the master channel and the pitch
+    /// bend range (RPN 0) on every member channel.
+    pub fn configuration(&self) -> Vec<[u8; 3]> {
+        let mut messages = rpn(self.master, 6, self.members);
+        for channel in self.free.iter().chain(self.busy.iter().map(|(channel, _)| channel)) {
+            messages.extend(rpn(*channel, 0, self.bend_range));
+        }
+        messages
+    }
+
+    /// The member channel to take from a note still sounding when every one is busy.
+    pub fn to_steal(&self) -> Option<u8> {
+        if self.free.is_empty() {
+            self.busy.front().map(|&(channel, _)| channel)
+        } else {
+            None
+        }
+    }
+
+    /// Give a new note of `device` the least recently used free member channel.
+    pub fn allocate(&mut self, device: usize) -> Option<u8> {
+        let channel = self.free.pop_front()?;
+        self.busy.push_back((channel, device));
+        Some(channel)
+    }
+
+    /// The note on `channel` has ended.
+    pub fn release(&mut self, channel: u8) {
+        if let Some(index) = self.busy.iter().position(|&(busy, _)| busy == channel) {
+            self.busy.remove(index);
+            self.free.push_back(channel);
+        }
+    }
+
+    /// Every note has been silenced without note offs (panic).
+    pub fn reset(&mut self) {
+        self.free.extend(self.busy.drain(..).map(|(channel, _)| channel));
+    }
+
+    /// Member channels with a note that expression from `device` shapes: the
+    /// device's own notes, or in zone scope every note.
+    pub fn channels(&self, scope: MpeScope, device: usize) -> Vec<u8> {
+        self.busy
+            .iter()
+            .filter(|&&(_, from)| scope == MpeScope::Zone || from == device)
+            .map(|&(channel, _)| channel)
+            .collect()
+    }
+
+    pub fn set_expression(&mut self, scope: MpeScope, device: usize, expression: MpeExpression, value: u32) {
+        let source = match scope {
+            MpeScope::Device => Some(device),
+            MpeScope::Zone => None,
+        };
+        self.expression.insert((source, expression), value);
+    }
+
+    /// What to send on a member channel before a new note of `device` starts
+    /// there, so it does not inherit the previous note's expression. The
+    /// device's own expression wins over the zone's.
+    pub fn initial_expression(&self, channel: u8, device: usize) -> Vec<(Vec<u8>, Voice)> {
+        [MpeExpression::Pitch, MpeExpression::Timbre, MpeExpression::Pressure]
+            .into_iter()
+            .map(|expression| {
+                let default = if expression == MpeExpression::Pressure { 0 } else { CENTRE };
+                let value = self
+                    .expression
+                    .get(&(Some(device), expression))
+                    .or_else(|| self.expression.get(&(None, expression)))
+                    .copied()
+                    .unwrap_or(default);
+                expression_message(expression, channel, value)
+            })
+            .collect()
+    }
+}
+
+/// The message for an expression at 32-bit resolution on `channel`, in its
+/// MIDI 1.0 and MIDI 2.0 forms.
+pub fn expression_message(expression: MpeExpression, channel: u8, value: u32) -> (Vec<u8>, Voice) {
+    let voice = match expression {
+        MpeExpression::Pitch => Voice::PitchBend { channel, value },
+        MpeExpression::Timbre => Voice::ControlChange {
+            channel,
+            control: TIMBRE,
+            value,
+        },
+        MpeExpression::Pressure => Voice::ChannelPressure { channel, value },
+    };
+    (voice.to_midi1().unwrap_or_default(), voice)
+}
+
+/// Set a registered parameter, then deselect it so stray data entry does no harm.
+fn rpn(channel: u8, parameter: u8, value: u8) -> Vec<[u8; 3]> {
+    let status = CONTROL_CHANGE | channel;
+    vec![[status, 101, 0], [status, 100, parameter], [status, 6, value], [status, 101, 127], [status, 100, 127]]
+}
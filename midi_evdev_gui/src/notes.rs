@@ -0,0 +1,63 @@
+use evdev_rs::enums::EventCode;
+use midi_types::status::{NOTE_OFF, NOTE_ON};
+use std::collections::BTreeMap;
+
+/// The key, on a given profile device, that is holding a note down.
+pub type Holder = (usize, EventCode);
+
+/// A sounding note: output index, channel, note number.
+pub type SoundingNote = (usize, u8, u8);
+
+/// Every note we have started and not yet ended, so that none are left hanging
+/// when a key's mapping changes under it, its device goes away or we exit.
+#[derive(Debug, Default)]
+pub struct NoteTracker {
+    sounding: BTreeMap<SoundingNote, Option<Holder>>,
+}
+
+impl NoteTracker {
+    /// Keep track of a message about to go to `output`.
+    pub fn observe(&mut self, output: usize, message: &[u8], holder: Option<Holder>) {
+        match *message {
+            [status, note, velocity] if status & 0xF0 == NOTE_ON && velocity > 0 => {
+                self.sounding.insert((output, status & 0x0F, note), holder);
+            }
+            [status, note, _] if status & 0xF0 == NOTE_OFF || status & 0xF0 == NOTE_ON => {
+                self.sounding.remove(&(output, status & 0x0F, note));
+            }
+            _ => {}
+        }
+    }
+
+    pub fn sounding(&self) -> impl Iterator<Item = SoundingNote> + '_ {
+        self.sounding.keys().copied()
+    }
+
+    /// Forget, and return, the notes whose holder matches.
+    pub fn take(&mut self, mut matches: impl FnMut(Option<&Holder>) -> bool) -> Vec<SoundingNote> {
+        let notes: Vec<_> = self
+            .sounding
+            .iter()
+            .filter(|(_, holder)| matches(holder.as_ref()))
+            .map(|(&note, _)| note)
+            .collect();
+        for note in &notes {
+            self.sounding.remove(note);
+        }
+        notes
+    }
+
+    /// Forget, and return, the notes `holder` holds whose number is in `numbers`.
+    pub fn take_held(&mut self, holder: Holder, numbers: &[u8]) -> Vec<SoundingNote> {
+        let notes: Vec<_> = self
+            .sounding
+            .iter()
+            .filter(|&(&(_, _, note), held_by)| *held_by == Some(holder) && numbers.contains(&note))
+            .map(|(&note, _)| note)
+            .collect();
+        for note in &notes {
+            self.sounding.remove(note);
+        }
+        notes
+    }
+}
@@ -0,0 +1,70 @@
+use crate::profile::OscOutputConfig;
+use color_eyre::eyre::{Result, WrapErr};
+use rosc::{encoder, OscBundle, OscMessage, OscPacket, OscTime};
+use std::net::UdpSocket;
+
+/// OSC's "immediately" time tag.
+const IMMEDIATELY: OscTime = OscTime {
+    seconds: 0,
+    fractional: 1,
+};
+
+/// OSC messages over UDP to one host and port.
+pub struct OscOutput {
+    name: String,
+    socket: UdpSocket,
+    /// Collect messages until the end of the input frame and send them as one bundle.
+    bundle: bool,
+    pending: Vec<OscPacket>,
+}
+
+impl OscOutput {
+    pub fn open(config: &OscOutputConfig) -> Result<Self> {
+        let socket = UdpSocket::bind(("0.0.0.0", 0)).wrap_err("Failed to open a UDP socket for OSC")?;
+        socket
+            .connect((config.host.as_str(), config.port))
+            .wrap_err_with(|| format!("Failed to resolve OSC output {}:{}", config.host, config.port))?;
+        println!("Sending OSC to {}:{}", config.host, config.port);
+        Ok(Self {
+            name: config.name.clone(),
+            socket,
+            bundle: config.bundle,
+            pending: Vec::new(),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn send(&mut self, message: OscMessage) {
+        let packet = OscPacket::Message(message);
+        if self.bundle {
+            self.pending.push(packet);
+        } else {
+            self.write(&packet);
+        }
+    }
+
+    /// The input frame is complete (`SYN_REPORT`): send what it produced.
+    pub fn flush(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+        let bundle = OscPacket::Bundle(OscBundle {
+            timetag: IMMEDIATELY,
+            content: std::mem::take(&mut self.pending),
+        });
+        self.write(&bundle);
+    }
+
+    fn write(&self, packet: &OscPacket) {
+        let result = encoder::encode(packet)
+            .map_err(|e| e.to_string())
+            .and_then(|bytes| self.socket.send(&bytes).map_err(|e| e.to_string()));
+        // Nobody listening is not worth stopping for, UDP drops it anyway
+        if let Err(e) = result {
+            eprintln!("Failed to send OSC on {}: {e}", self.name);
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use color_eyre::eyre::{Result, WrapErr};
+use rosc::{decoder, encoder, OscMessage, OscPacket, OscType};
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::thread;
+
+/// Load another profile's mappings: `/js-midi/profile <path>`.
+pub const PROFILE: &str = "/js-midi/profile";
+/// Switch layer, or leave layers with an empty name: `/js-midi/layer <name>`.
+pub const LAYER: &str = "/js-midi/layer";
+/// Press (1, true) or release (0, false) a key: `/js-midi/button <device> <code> <value>`.
+pub const BUTTON: &str = "/js-midi/button";
+/// Move an axis, 0.0-1.0 or a raw int: `/js-midi/axis <device> <code> <value>`.
+pub const AXIS: &str = "/js-midi/axis";
+/// Ask for the engine's state, which is sent back to the asker as a bundle.
+pub const QUERY: &str = "/js-midi/query";
+
+/// An OSC message received by the server, with a way to answer it.
+#[derive(Debug)]
+pub struct OscInEvent {
+    pub message: OscMessage,
+    pub reply: OscReply,
+}
+
+/// Sends packets back to where a message came from.
+#[derive(Debug, Clone)]
+pub struct OscReply {
+    socket: Arc<UdpSocket>,
+    to: SocketAddr,
+}
+
+impl OscReply {
+    pub fn send(&self, packet: &OscPacket) {
+        let result = encoder::encode(packet)
+            .map_err(|e| e.to_string())
+            .and_then(|bytes| self.socket.send_to(&bytes, self.to).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            eprintln!("Failed to answer OSC query from {}: {e}", self.to);
+        }
+    }
+}
+
+/// Listen for OSC on `address` (e.g. `127.0.0.1:9000`) and forward every
+/// message, bundles unpacked, to the engine's queue.
+pub fn spawn_server<T>(address: &str, tx: Sender<T>) -> Result<()>
+where
+    T: From<OscInEvent> + Send + 'static,
+{
+    let socket = Arc::new(UdpSocket::bind(address).wrap_err_with(|| format!("Failed to listen for OSC on {address}"))?);
+    println!("Listening for OSC on {address}");
+
+    thread::spawn(move || {
+        let mut buf = [0; decoder::MTU];
+        loop {
+            let (len, from) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) => {
+                    eprintln!("OSC server stopped: {e}");
+                    return;
+                }
+            };
+            let packet = match decoder::decode_udp(&buf[..len]) {
+                Ok((_, packet)) => packet,
+                Err(e) => {
+                    eprintln!("Ignoring malformed OSC packet from {from}: {e}");
+                    continue;
+                }
+            };
+
+            let reply = OscReply {
+                socket: Arc::clone(&socket),
+                to: from,
+            };
+            for message in messages(packet) {
+                if tx.send(OscInEvent { message, reply: reply.clone() }.into()).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/// The messages of a packet, in order, however deeply bundled.
+fn messages(packet: OscPacket) -> Vec<OscMessage> {
+    match packet {
+        OscPacket::Message(message) => vec![message],
+        OscPacket::Bundle(bundle) => bundle.content.into_iter().flat_map(messages).collect(),
+    }
+}
+
+pub fn arg_str(args: &[OscType], index: usize) -> Option<&str> {
+    match args.get(index) {
+        Some(OscType::String(s)) => Some(s),
+        _ => None,
+    }
+}
+
+/// A numeric or boolean argument as a float; true is 1.0.
+pub fn arg_number(args: &[OscType], index: usize) -> Option<f64> {
+    match *args.get(index)? {
+        OscType::Int(value) => Some(f64::from(value)),
+        OscType::Long(value) => i32::try_from(value).ok().map(f64::from),
+        OscType::Float(value) => Some(f64::from(value)),
+        OscType::Double(value) => Some(value),
+        OscType::Bool(value) => Some(f64::from(u8::from(value))),
+        _ => None,
+    }
+}
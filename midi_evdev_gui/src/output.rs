@@ -0,0 +1,117 @@
+use crate::ports::{self, PortSelector};
+use crate::sink::MidiSink;
+use color_eyre::eyre::{eyre, Result};
+use midir::os::unix::VirtualOutput;
+use midir::{MidiOutput, MidiOutputConnection};
+use std::time::Duration;
+
+/// ALSA sequencer client name; virtual ports show up under it.
+pub const CLIENT_NAME: &str = "js-midi";
+
+/// The port a MIDI output (or input) is connected to.
+#[derive(Debug, Clone)]
+pub enum Destination {
+    /// Connect to a port that already exists.
+    Port(PortSelector),
+    /// Publish a port of our own that DAWs can connect to like any MIDI device.
+    Virtual(String),
+}
+
+/// A MIDI output connection that survives its port going away.
+///
+/// When the port disappears (the synth is power-cycled, say) the output goes
+/// offline and [`MidiSink::poll`] keeps looking for it until it can reconnect.
+pub struct Output {
+    name: String,
+    /// How to find the port again after losing it.
+    destination: Destination,
+    connection: Option<MidiOutputConnection>,
+    /// Client used only to list ports while checking on the connection.
+    probe: MidiOutput,
+}
+
+impl Output {
+    /// Open `destination`, or the first available port if there is none.
+    pub fn open(destination: Option<&Destination>) -> Result<Self> {
+        let probe = MidiOutput::new(CLIENT_NAME).map_err(|e| eyre!("Failed to create MIDI output: {e}"))?;
+
+        let destination = if let Some(destination) = destination {
+            destination.clone()
+        } else {
+            let port = probe.ports().into_iter().next().ok_or_else(|| eyre!("No MIDI output ports available"))?;
+            let name = probe.port_name(&port).unwrap_or_else(|_| "Unknown port".to_string());
+            println!("No MIDI port selected, using {name} (see --port and list-ports)");
+            Destination::Port(PortSelector::Name(name))
+        };
+
+        let (name, connection) = connect(&destination)?;
+        Ok(Self {
+            name,
+            destination,
+            connection: Some(connection),
+            probe,
+        })
+    }
+}
+
+fn connect(destination: &Destination) -> Result<(String, MidiOutputConnection)> {
+    let midi_out = MidiOutput::new(CLIENT_NAME).map_err(|e| eyre!("Failed to create MIDI output: {e}"))?;
+
+    match destination {
+        Destination::Virtual(name) => {
+            let connection = midi_out
+                .create_virtual(name)
+                .map_err(|e| eyre!("Failed to create virtual MIDI port '{name}': {e}"))?;
+            println!("Created virtual MIDI port {name}");
+            Ok((name.clone(), connection))
+        }
+        Destination::Port(selector) => {
+            let (port, name) = ports::find_port(&midi_out, selector)?;
+            let connection = midi_out
+                .connect(&port, "midir-test")
+                .map_err(|e| eyre!("Failed to connect MIDI output {name}: {e}"))?;
+            Ok((name, connection))
+        }
+    }
+}
+
+impl MidiSink for Output {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn send(&mut self, _timestamp: Duration, message: &[u8]) -> Result<()> {
+        let connection = self.connection.as_mut().ok_or_else(|| eyre!("{} is offline", self.name))?;
+        connection.send(message).map_err(|e| {
+            self.connection = None;
+            eyre!("Failed to send MIDI message to {}: {e}", self.name)
+        })
+    }
+
+    fn is_online(&self) -> bool {
+        self.connection.is_some()
+    }
+
+    fn poll(&mut self) -> bool {
+        match (&self.connection, &self.destination) {
+            (Some(_), Destination::Port(selector)) => {
+                // ALSA quietly drops messages for a vanished port, so look for it ourselves
+                if ports::find_port(&self.probe, selector).is_err() {
+                    println!("MIDI port {} went away, waiting for it to come back", self.name);
+                    self.connection = None;
+                }
+                false
+            }
+            (Some(_), Destination::Virtual(_)) => false,
+            (None, destination) => match connect(destination) {
+                Ok((name, connection)) => {
+                    println!("Reconnected to MIDI port {name}");
+                    self.name = name;
+                    self.connection = Some(connection);
+                    true
+                }
+                Err(_) => false,
+            },
+        }
+    }
+}
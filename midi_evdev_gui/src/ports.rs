@@ -0,0 +1,135 @@
+use color_eyre::eyre::{eyre, Result};
+use midir::MidiIO;
+use regex::Regex;
+use std::fmt;
+use std::str::FromStr;
+
+/// How a MIDI port is picked out of the ports ALSA currently enumerates.
+///
+/// Written as `/pattern/` for a regex; anything else matches a port with exactly
+/// that name, or failing that the single port whose name contains it.
+#[derive(Debug, Clone)]
+pub enum PortSelector {
+    Name(String),
+    Regex(Regex),
+}
+
+impl FromStr for PortSelector {
+    type Err = regex::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix('/').and_then(|rest| rest.strip_suffix('/')) {
+            Some(pattern) => Ok(Self::Regex(Regex::new(pattern)?)),
+            None => Ok(Self::Name(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for PortSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Name(name) => write!(f, "{name}"),
+            Self::Regex(regex) => write!(f, "/{regex}/"),
+        }
+    }
+}
+
+impl PortSelector {
+    /// Pick the matching port out of `(port, name)` pairs.
+    fn select<'a, P>(&self, ports: &'a [(P, String)]) -> Result<&'a (P, String), Vec<&'a str>> {
+        let candidates: Vec<_> = match self {
+            Self::Name(name) => {
+                if let Some(exact) = ports.iter().find(|(_, port_name)| port_name == name) {
+                    return Ok(exact);
+                }
+                ports.iter().filter(|(_, port_name)| port_name.contains(name.as_str())).collect()
+            }
+            Self::Regex(regex) => ports.iter().filter(|(_, port_name)| regex.is_match(port_name)).collect(),
+        };
+
+        match candidates.as_slice() {
+            [port] => Ok(port),
+            _ => Err(candidates.iter().map(|(_, port_name)| port_name.as_str()).collect()),
+        }
+    }
+}
+
+/// The name `io` gives `port`.
+pub fn port_name<T: MidiIO>(io: &T, port: &T::Port) -> String {
+    io.port_name(port).unwrap_or_else(|_| "Unknown port".to_string())
+}
+
+/// Find the single port matching `selector`, returning it with its name.
+///
+/// The error lists the available ports, or the ambiguous matches.
+pub fn find_port<T: MidiIO>(io: &T, selector: &PortSelector) -> Result<(T::Port, String)> {
+    // Name the ports from the same enumeration, so names can't shift onto other
+    // ports when one appears or disappears in between
+    let ports: Vec<_> = io
+        .ports()
+        .into_iter()
+        .map(|port| {
+            let name = port_name(io, &port);
+            (port, name)
+        })
+        .collect();
+
+    match selector.select(&ports) {
+        Ok(found) => Ok(found.clone()),
+        Err(matches) if matches.is_empty() => Err(eyre!(
+            "No MIDI port matches '{selector}'. Available ports:{}",
+            format_list(ports.iter().map(|(_, name)| name.as_str()))
+        )),
+        Err(matches) => Err(eyre!(
+            "MIDI port '{selector}' is ambiguous, it matches:{}",
+            format_list(matches.into_iter())
+        )),
+    }
+}
+
+fn format_list<'a>(names: impl Iterator<Item = &'a str>) -> String {
+    let list: String = names.flat_map(|name| ["\n  ", name]).collect();
+    if list.is_empty() {
+        " (none)".to_string()
+    } else {
+        list
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ports(names: &[&str]) -> Vec<((), String)> {
+        names.iter().map(|name| ((), (*name).to_string())).collect()
+    }
+
+    fn selector(s: &str) -> PortSelector {
+        s.parse().expect("selector parses")
+    }
+
+    #[test]
+    fn exact_name_wins_over_substring() {
+        let ports = ports(&["Synth", "Synth MIDI 1", "Synth MIDI 2"]);
+        assert_eq!(selector("Synth").select(&ports).map(|((), name)| name.as_str()), Ok("Synth"));
+        assert_eq!(selector("MIDI 2").select(&ports).map(|((), name)| name.as_str()), Ok("Synth MIDI 2"));
+    }
+
+    #[test]
+    fn ambiguous_or_missing_substring_is_an_error() {
+        let ports = ports(&["Synth MIDI 1", "Synth MIDI 2", "Drums"]);
+        assert_eq!(selector("MIDI").select(&ports).err(), Some(vec!["Synth MIDI 1", "Synth MIDI 2"]));
+        assert_eq!(selector("Bass").select(&ports).err(), Some(Vec::new()));
+    }
+
+    #[test]
+    fn slashes_make_a_regex() {
+        let ports = ports(&["Synth MIDI 1", "Synth MIDI 2"]);
+        let regex = selector("/MIDI 2$/");
+        assert!(matches!(regex, PortSelector::Regex(_)));
+        assert_eq!(regex.to_string(), "/MIDI 2$/");
+        assert_eq!(regex.select(&ports).map(|((), name)| name.as_str()), Ok("Synth MIDI 2"));
+        assert!(matches!(selector("/MIDI"), PortSelector::Name(_)));
+        assert!("/MIDI (/".parse::<PortSelector>().is_err());
+    }
+}
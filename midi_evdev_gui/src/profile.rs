@@ -0,0 +1,508 @@
+use crate::macros::MacroConfig;
+use crate::chord::{Chord, VoicingControl};
+use crate::mackie::Button;
+use crate::midi_utils::MidiCC;
+use crate::output::Destination;
+use crate::scale::{Pitch, Scale};
+use crate::sysex::SysexTemplate;
+use crate::transport::{MmcCommand, Realtime};
+use crate::virtual_axis::Combine;
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use serde::{Deserialize, Deserializer};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const DEFAULT_DEVICE: &str = "/dev/input/event27";
+
+/// A mapping profile, loaded from a TOML file.
+#[derive(Debug, Deserialize)]
+pub struct Profile {
+    #[serde(default)]
+    pub devices: Vec<DeviceConfig>,
+    #[serde(default)]
+    pub outputs: Vec<OutputConfig>,
+    #[serde(default)]
+    pub osc_outputs: Vec<OscOutputConfig>,
+    /// MIDI inputs, typically feedback from the DAW.
+    #[serde(default)]
+    pub inputs: Vec<InputConfig>,
+    /// Axes combined from several of a device's axes and keys.
+    #[serde(default)]
+    pub virtual_axes: Vec<VirtualAxisConfig>,
+    /// Timed sequences of messages that buttons start and stop.
+    #[serde(default)]
+    pub macros: Vec<MacroConfig>,
+    #[serde(default = "default_mappings")]
+    pub mappings: Vec<MappingConfig>,
+    /// Where `toggle_recording` saves Standard MIDI Files; relative to the profile.
+    pub recording: Option<PathBuf>,
+    /// Run an internal MIDI clock.
+    pub clock: Option<ClockConfig>,
+    /// The key `degree` mappings play in.
+    pub key: Option<KeyConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct DeviceConfig {
+    /// Name that mappings use to refer to this device.
+    pub name: Option<String>,
+    pub path: PathBuf,
+    /// Take an exclusive grab (EVIOCGRAB) so games and the desktop stop seeing the device.
+    #[serde(default)]
+    pub grab: bool,
+    /// Send this device's events to a virtual port of its own instead of the first output.
+    pub virtual_port: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct OutputConfig {
+    /// Name that mappings use to route to this output.
+    pub name: String,
+    /// Port to connect to: exact name, unique substring or `/regex/`.
+    pub port: Option<String>,
+    /// Name of a virtual port to publish instead of connecting to `port`.
+    pub virtual_port: Option<String>,
+    /// Publish an RTP-MIDI network session named after the output on this UDP
+    /// port (and the next one, for data) instead of using a local port.
+    pub rtp_midi: Option<u16>,
+    /// Write MIDI 2.0 Universal MIDI Packets to this ALSA UMP device
+    /// (`/dev/snd/umpC1D0`) instead of using a MIDI 1.0 port.
+    pub ump: Option<PathBuf>,
+    /// Treat the output as an MPE zone: every note gets a member channel of its
+    /// own so `mpe` mappings can bend and shape it alone.
+    pub mpe: Option<MpeConfig>,
+    /// Act as a Mackie Control surface towards the DAW. The DAW's side comes
+    /// back on an input whose `output` is this one, which answers its handshake.
+    #[serde(default)]
+    pub mackie: bool,
+}
+
+impl OutputConfig {
+    pub fn destination(&self) -> Result<Option<Destination>> {
+        let local = self.port.is_some() || self.virtual_port.is_some();
+        if [local, self.rtp_midi.is_some(), self.ump.is_some()].into_iter().filter(|&set| set).count() > 1 {
+            return Err(eyre!("Output {} can only have one of a port, rtp_midi or ump", self.name));
+        }
+        if let Some(mpe) = &self.mpe {
+            if !(1..=15).contains(&mpe.members) {
+                return Err(eyre!("Output {} needs 1 to 15 MPE member channels", self.name));
+            }
+        }
+        destination(self.port.as_deref(), self.virtual_port.as_deref())
+            .wrap_err_with(|| format!("Invalid output {}", self.name))
+    }
+}
+
+/// An MPE (MIDI Polyphonic Expression) zone, announced to the synth on start.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct MpeConfig {
+    #[serde(default)]
+    pub zone: MpeZoneKind,
+    /// Member channels, 1-15, next to the master channel.
+    #[serde(default = "default_mpe_members")]
+    pub members: u8,
+    /// Member channel pitch bend range in semitones.
+    #[serde(default = "default_bend_range")]
+    pub bend_range: u8,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MpeZoneKind {
+    /// Master channel 0, members counting up from 1.
+    #[default]
+    Lower,
+    /// Master channel 15, members counting down from 14.
+    Upper,
+}
+
+const fn default_mpe_members() -> u8 {
+    15
+}
+
+const fn default_bend_range() -> u8 {
+    48
+}
+
+/// The internal MIDI clock: 24 ticks per quarter note for as long as we run.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ClockConfig {
+    /// Starting tempo in BPM.
+    #[serde(default = "default_tempo")]
+    pub tempo: f64,
+    /// Outputs that get the ticks; by default the first.
+    #[serde(default)]
+    pub outputs: Vec<String>,
+}
+
+/// An axis computed from a device's axes and keys once per frame, which
+/// mappings use as `virtual_axis = "name"` like a real axis.
+#[derive(Debug, Deserialize)]
+pub struct VirtualAxisConfig {
+    pub name: String,
+    /// Only compute it for the device with this name; by default for every device.
+    pub device: Option<String>,
+    #[serde(flatten)]
+    pub combine: Combine,
+}
+
+/// A root note and a scale, e.g. `root = "D3"`, `scale = "dorian"`.
+#[derive(Debug, Deserialize)]
+pub struct KeyConfig {
+    /// Defaults to middle C, C4.
+    #[serde(default = "default_root")]
+    pub root: Pitch,
+    #[serde(default)]
+    pub scale: Scale,
+}
+
+const fn default_root() -> Pitch {
+    Pitch(60)
+}
+
+const fn default_tempo() -> f64 {
+    120.0
+}
+
+/// Where OSC mappings send to, over UDP.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct OscOutputConfig {
+    /// Name that OSC mappings use to route to this output.
+    pub name: String,
+    #[serde(default = "default_osc_host")]
+    pub host: String,
+    pub port: u16,
+    /// Send everything one input frame (up to its `SYN_REPORT`) produces as one bundle.
+    #[serde(default)]
+    pub bundle: bool,
+}
+
+fn default_osc_host() -> String {
+    "127.0.0.1".to_string()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct InputConfig {
+    /// Port to listen to: exact name, unique substring or `/regex/`.
+    pub port: Option<String>,
+    /// Name of a virtual port to publish for the DAW to send to instead.
+    pub virtual_port: Option<String>,
+    /// Output whose controllers this input reports on; by default the first.
+    pub output: Option<String>,
+    #[serde(default)]
+    pub actions: Vec<ActionConfig>,
+    /// Outputs to forward everything received here to, merged with the joystick's messages.
+    #[serde(default)]
+    pub thru: Vec<String>,
+    /// Only forward these channels (0-based); by default all of them.
+    #[serde(default)]
+    pub channels: Vec<u8>,
+    /// `[from, to]` channel pairs to move messages to on the way through.
+    #[serde(default)]
+    pub remap: Vec<(u8, u8)>,
+}
+
+impl InputConfig {
+    pub fn destination(&self) -> Result<Destination> {
+        destination(self.port.as_deref(), self.virtual_port.as_deref())?
+            .ok_or_else(|| eyre!("Input needs a port or a virtual_port"))
+    }
+}
+
+/// Something to do when a given note arrives on an input.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct ActionConfig {
+    pub note: u8,
+    /// Only react on this channel (0-based); by default on any.
+    pub channel: Option<u8>,
+    /// Switch to this mapping layer.
+    pub layer: String,
+}
+
+fn destination(port: Option<&str>, virtual_port: Option<&str>) -> Result<Option<Destination>> {
+    match (port, virtual_port) {
+        (Some(_), Some(_)) => Err(eyre!("Only one of port and virtual_port can be set")),
+        (Some(port), None) => Ok(Some(Destination::Port(port.parse()?))),
+        (None, Some(name)) => Ok(Some(Destination::Virtual(name.to_string()))),
+        (None, None) => Ok(None),
+    }
+}
+
+/// One input (an axis or a key) and what it turns into.
+#[derive(Debug, Clone, Deserialize)]
+pub struct MappingConfig {
+    /// Only react to the device with this name; by default every device.
+    pub device: Option<String>,
+    /// Only active while this layer is selected; by default always active.
+    pub layer: Option<String>,
+    #[serde(flatten)]
+    pub source: Source,
+    #[serde(flatten)]
+    pub target: Target,
+    /// MIDI channel, 0-based.
+    #[serde(default)]
+    pub channel: u8,
+    /// Outputs to send to. By default the device's virtual port, or the first output.
+    #[serde(default)]
+    pub outputs: Vec<String>,
+    /// Soft takeover: once the DAW has moved the controller, stay quiet until
+    /// the axis reaches the DAW's value instead of jumping back.
+    #[serde(default)]
+    pub takeover: bool,
+}
+
+/// An evdev code, by its kernel name (`ABS_X`, `BTN_BASE6`, ...).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Source {
+    Axis(String),
+    Key(String),
+    /// One of the profile's `virtual_axes`, by name.
+    VirtualAxis(String),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Target {
+    /// Control change with the axis scaled to 0-127, by number or name (`"FilterCutoff"`).
+    Cc(MidiCC),
+    /// Note on while the key is held.
+    Note(u8),
+    /// Note on of this degree of the profile's key while the key is held: 0 is
+    /// the root, 7 the octave above in a seven-note scale, -1 the note below the root.
+    Degree(i8),
+    /// A chord on a degree of the profile's key while the key is held, voiced as
+    /// the `voicing` mappings last set.
+    Chord(ChordTarget),
+    /// Set how chords are voiced: an axis across the range, a hat a step up or
+    /// down, a key to the next step and round again. Held chords are re-voiced.
+    Voicing(VoicingControl),
+    /// Move the key by this many semitones, e.g. 1 or 12, when the key is pressed.
+    /// Pushing a hat right or down moves it by this much, left or up the other way.
+    Transpose(i8),
+    /// MIDI 2.0 per-note pitch bend of this note, centred axis for no bend.
+    /// MIDI 1.0 outputs do not get it.
+    NotePitch(u8),
+    /// MIDI 2.0 registered per-note controller. MIDI 1.0 outputs do not get it.
+    NoteController { note: u8, controller: u8 },
+    /// Per-note expression from an axis on MPE outputs: it shapes the notes the
+    /// same device's keys are holding, or with `scope = "zone"` every note in the
+    /// zone. Other outputs get it on `channel`.
+    Mpe(MpeTarget),
+    /// A Mackie Control button, e.g. `"play"`, `"bank_right"` or `"select_3"`.
+    MackieButton(Button),
+    /// A Mackie Control fader: 0-7 for the strips, 8 for master.
+    MackieFader(u8),
+    /// MIDI Machine Control when the key is pressed: `"play"`, `"stop"`, `"record"`,
+    /// ..., or `{ locate = "hh:mm:ss:ff" }`.
+    Mmc(MmcCommand),
+    /// System realtime Start, Continue or Stop when the key is pressed.
+    Realtime(Realtime),
+    /// The clock's tempo from an axis, across a range of BPM.
+    Tempo(TempoRange),
+    /// Keys change the clock's tempo by this many BPM per press; axes bend it by
+    /// up to this much either side of centre for as long as they are moved.
+    TempoNudge(f64),
+    /// Hand the input to a Rhai script function, which returns the messages to send.
+    Script(ScriptTarget),
+    /// Start one of the profile's `macros`, by name, when the key is pressed, or
+    /// cancel it if it is still running.
+    Macro(String),
+    /// SysEx built from a template. Axes insert their value scaled to 0-127,
+    /// keys 127 when pressed and 0 when released.
+    Sysex(SysexTemplate),
+    /// The SysEx messages of a `.syx` file, sent when the key is pressed.
+    /// Relative paths are relative to the profile.
+    Syx(PathBuf),
+    /// OSC message to an `osc_outputs` entry; `outputs` names those instead of MIDI outputs.
+    Osc(OscTarget),
+    /// Something for the engine to do when the key is pressed.
+    Action(Action),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MpeExpression {
+    /// Pitch bend, centred axis for no bend.
+    Pitch,
+    /// CC74.
+    Timbre,
+    /// Channel pressure.
+    Pressure,
+}
+
+/// Which notes an `mpe` mapping shapes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MpeScope {
+    /// The notes held by keys of the device the mapping's axis is on.
+    #[default]
+    Device,
+    /// Every note sounding in the zone, whichever device plays it.
+    Zone,
+}
+
+/// `"pressure"`, or `{ expression = "pressure", scope = "zone" }`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MpeTarget {
+    pub expression: MpeExpression,
+    pub scope: MpeScope,
+}
+
+impl<'de> Deserialize<'de> for MpeTarget {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Expression(MpeExpression),
+            Scoped {
+                expression: MpeExpression,
+                #[serde(default)]
+                scope: MpeScope,
+            },
+        }
+
+        Ok(match Raw::deserialize(deserializer)? {
+            Raw::Expression(expression) => Self {
+                expression,
+                scope: MpeScope::Device,
+            },
+            Raw::Scoped { expression, scope } => Self { expression, scope },
+        })
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChordTarget {
+    pub shape: Chord,
+    /// Degree of the key the chord is built on; by default its root.
+    #[serde(default)]
+    pub degree: i8,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ScriptTarget {
+    /// Rhai file, relative to the profile. It is reloaded whenever it changes.
+    pub file: PathBuf,
+    /// Function called with the input, once per event of the mapping's code.
+    #[serde(default = "default_script_function")]
+    pub function: String,
+}
+
+fn default_script_function() -> String {
+    "transform".to_string()
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct TempoRange {
+    pub min: f64,
+    pub max: f64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct OscTarget {
+    /// Address pattern; `{device}` and `{code}` are replaced by the device's
+    /// name and the evdev code, e.g. `/js/{device}/{code}`.
+    pub address: String,
+    #[serde(default)]
+    pub arg: OscArg,
+}
+
+/// The argument an OSC mapping sends.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OscArg {
+    /// Axes from 0.0 to 1.0 at full resolution, keys 0.0 or 1.0.
+    #[default]
+    Float,
+    /// The raw evdev value.
+    Int,
+    /// Whether the value is non-zero.
+    Bool,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// All Notes Off and Reset All Controllers on every channel of every output.
+    Panic,
+    /// Start recording everything sent to a Standard MIDI File, or stop and save it.
+    ToggleRecording,
+    /// Set the clock's tempo from the time between presses.
+    TapTempo,
+}
+
+impl Profile {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .wrap_err_with(|| format!("Failed to read profile {}", path.display()))?;
+        let mut profile: Self =
+            toml::from_str(&text).wrap_err_with(|| format!("Failed to parse profile {}", path.display()))?;
+
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        if let Some(recording) = &mut profile.recording {
+            *recording = dir.join(&recording);
+        }
+        for mapping in &mut profile.mappings {
+            match &mut mapping.target {
+                Target::Syx(file) | Target::Script(ScriptTarget { file, .. }) => *file = dir.join(&file),
+                _ => {}
+            }
+        }
+        Ok(profile)
+    }
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self {
+            devices: vec![DeviceConfig {
+                name: None,
+                path: PathBuf::from(DEFAULT_DEVICE),
+                grab: false,
+                virtual_port: None,
+            }],
+            outputs: Vec::new(),
+            osc_outputs: Vec::new(),
+            inputs: Vec::new(),
+            virtual_axes: Vec::new(),
+            macros: Vec::new(),
+            mappings: default_mappings(),
+            recording: None,
+            clock: None,
+            key: None,
+        }
+    }
+}
+
+/// The mappings used when a profile does not list any.
+fn default_mappings() -> Vec<MappingConfig> {
+    let axis = |name: &str, control: MidiCC| MappingConfig {
+        device: None,
+        layer: None,
+        source: Source::Axis(name.to_string()),
+        target: Target::Cc(control),
+        channel: 0,
+        outputs: Vec::new(),
+        takeover: false,
+    };
+    let key = |name: &str, note: u8| MappingConfig {
+        device: None,
+        layer: None,
+        source: Source::Key(name.to_string()),
+        target: Target::Note(note),
+        channel: 0,
+        outputs: Vec::new(),
+        takeover: false,
+    };
+
+    vec![
+        axis("ABS_X", MidiCC::Pan),
+        axis("ABS_Y", MidiCC::Volume),
+        axis("ABS_RX", MidiCC::ModulationWheel),
+        axis("ABS_RY", MidiCC::Expression),
+        key("BTN_BASE6", 60),
+        // KEY_UNKNOWN as placeholder for custom key codes
+        key("KEY_UNKNOWN", 60),
+    ]
+}
@@ -0,0 +1,552 @@
+use crate::sink::MidiSink;
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use midi_types::status::{CONTROL_CHANGE, NOTE_OFF, NOTE_ON};
+use std::collections::BTreeMap;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// AppleMIDI session commands start with `FF FF` and two letters.
+const SIGNATURE: [u8; 2] = [0xFF, 0xFF];
+const PROTOCOL_VERSION: u32 = 2;
+/// Dynamic RTP payload type AppleMIDI uses for MIDI.
+const PAYLOAD_TYPE: u8 = 0x61;
+/// The MIDI command section length field is 12 bits.
+const MAX_COMMAND_LEN: usize = 0x0FFF;
+
+/// An RTP-MIDI (AppleMIDI) network session that peers such as macOS Network
+/// MIDI or rtpmidid can join.
+///
+/// We only answer: peers send the invitations and lead the clock sync. Every
+/// message goes to every joined peer with a recovery journal of the notes and
+/// controllers they have not acknowledged yet, so a lost packet cannot leave a
+/// note hanging.
+pub struct RtpMidiSession {
+    name: String,
+    control: UdpSocket,
+    data: UdpSocket,
+    shared: Arc<Mutex<Session>>,
+    ssrc: u32,
+    start: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Session {
+    peers: Vec<Peer>,
+    /// Count of data packets sent; the RTP sequence number is its low 16 bits.
+    sent: u64,
+    journal: Journal,
+    /// A peer finished joining since the last poll.
+    joined: bool,
+}
+
+#[derive(Debug)]
+struct Peer {
+    ssrc: u32,
+    name: String,
+    control: SocketAddr,
+    /// Known once the peer has also invited us on the data port.
+    data: Option<SocketAddr>,
+    /// The last packet the peer has reported receiving everything up to.
+    acknowledged: Option<u64>,
+}
+
+/// What the session's sockets need to answer on their own threads.
+#[derive(Clone)]
+struct Responder {
+    name: String,
+    ssrc: u32,
+    start: Instant,
+    shared: Arc<Mutex<Session>>,
+}
+
+impl RtpMidiSession {
+    /// Publish a session called `name` on UDP `port` (control) and `port + 1` (data).
+    pub fn open(name: &str, port: u16) -> Result<Self> {
+        let data_port = port.checked_add(1).ok_or_else(|| eyre!("RTP-MIDI port {port} leaves no room for data"))?;
+        let bind = |port| UdpSocket::bind(("0.0.0.0", port)).wrap_err_with(|| format!("Failed to bind port {port}"));
+        let control = bind(port)?;
+        let data = bind(data_port)?;
+
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().subsec_nanos();
+        let responder = Responder {
+            name: name.to_string(),
+            ssrc: nanos ^ std::process::id().rotate_left(16),
+            start: Instant::now(),
+            shared: Arc::default(),
+        };
+        for (socket, is_data) in [(control.try_clone()?, false), (data.try_clone()?, true)] {
+            let responder = responder.clone();
+            thread::spawn(move || responder.serve(&socket, is_data));
+        }
+        println!("Published RTP-MIDI session {name} on UDP port {port}");
+
+        Ok(Self {
+            name: name.to_string(),
+            control,
+            data,
+            shared: responder.shared,
+            ssrc: responder.ssrc,
+            start: responder.start,
+        })
+    }
+
+    fn session(&self) -> MutexGuard<'_, Session> {
+        lock(&self.shared)
+    }
+}
+
+impl MidiSink for RtpMidiSession {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn send(&mut self, _timestamp: Duration, message: &[u8]) -> Result<()> {
+        if message.len() > MAX_COMMAND_LEN {
+            return Err(eyre!("{}: {} byte message is too long for RTP-MIDI", self.name, message.len()));
+        }
+        let mut session = self.session();
+        let sequence = session.sent;
+        session.sent += 1;
+
+        // The sequence number and timestamp are the low 16 and 32 bits
+        let mut packet = vec![0x80, PAYLOAD_TYPE];
+        packet.extend_from_slice(&sequence.to_be_bytes()[6..]);
+        packet.extend_from_slice(&clock(self.start).to_be_bytes()[4..]);
+        packet.extend_from_slice(&self.ssrc.to_be_bytes());
+
+        // The journal covers the packets before this one
+        let journal = session.journal.encode();
+        session.journal.observe(sequence, message);
+        let peers: Vec<_> = session.peers.iter().filter_map(|peer| Some((peer.name.clone(), peer.data?))).collect();
+        drop(session);
+        let flags = if journal.is_some() { 0x40 } else { 0x00 };
+        let [high, low] = u16::try_from(message.len())?.to_be_bytes();
+        if message.len() <= 0x0F {
+            packet.push(flags | low);
+        } else {
+            packet.push(0x80 | flags | high);
+            packet.push(low);
+        }
+        packet.extend_from_slice(message);
+        if let Some(journal) = journal {
+            packet.extend_from_slice(&journal);
+        }
+
+        // One unreachable peer should not cut off the others
+        for (name, address) in peers {
+            if let Err(e) = self.data.send_to(&packet, address) {
+                eprintln!("{}: failed to send to {name}: {e}", self.name);
+            }
+        }
+        Ok(())
+    }
+
+    fn is_online(&self) -> bool {
+        self.session().peers.iter().any(|peer| peer.data.is_some())
+    }
+
+    fn poll(&mut self) -> bool {
+        std::mem::take(&mut self.session().joined)
+    }
+}
+
+impl Drop for RtpMidiSession {
+    fn drop(&mut self) {
+        let session = self.session();
+        for peer in &session.peers {
+            let mut bye = command(*b"BY");
+            bye.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+            bye.extend_from_slice(&0u32.to_be_bytes());
+            bye.extend_from_slice(&self.ssrc.to_be_bytes());
+            let _ = self.control.send_to(&bye, peer.control);
+        }
+    }
+}
+
+impl Session {
+    /// Drop from the journal what every joined peer has acknowledged.
+    fn prune(&mut self) {
+        let joined = self.peers.iter().filter(|peer| peer.data.is_some());
+        // `None` sorts first, so one peer that has acknowledged nothing keeps it all
+        if let Some(Some(sequence)) = joined.map(|peer| peer.acknowledged).min() {
+            self.journal.acknowledge(sequence);
+        }
+    }
+}
+
+impl Responder {
+    /// Answer session commands arriving on one of the two ports until it fails.
+    fn serve(&self, socket: &UdpSocket, is_data: bool) {
+        let mut buf = [0; 1500];
+        loop {
+            let (len, from) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) => {
+                    eprintln!("RTP-MIDI session {} stopped: {e}", self.name);
+                    return;
+                }
+            };
+            // Anything else is MIDI from the peer, which an output has no use for
+            if let [0xFF, 0xFF, a, b, body @ ..] = &buf[..len] {
+                if let Some(reply) = self.handle([*a, *b], body, from, is_data) {
+                    if let Err(e) = socket.send_to(&reply, from) {
+                        eprintln!("RTP-MIDI session {}: failed to answer {from}: {e}", self.name);
+                    }
+                }
+            }
+        }
+    }
+
+    fn handle(&self, command_name: [u8; 2], body: &[u8], from: SocketAddr, is_data: bool) -> Option<Vec<u8>> {
+        match &command_name {
+            // Invitation: version, initiator token, SSRC, name
+            b"IN" => {
+                let token = read_u32(body, 4)?;
+                let ssrc = read_u32(body, 8)?;
+                let name = body.get(12..).unwrap_or_default().split(|&byte| byte == 0).next().unwrap_or_default();
+                let name = String::from_utf8_lossy(name).into_owned();
+
+                let mut session = lock(&self.shared);
+                if is_data {
+                    let peer = session.peers.iter_mut().find(|peer| peer.ssrc == ssrc)?;
+                    peer.data = Some(from);
+                    session.joined = true;
+                    println!("{name} joined RTP-MIDI session {}", self.name);
+                } else {
+                    session.peers.retain(|peer| peer.ssrc != ssrc);
+                    session.peers.push(Peer {
+                        ssrc,
+                        name,
+                        control: from,
+                        data: None,
+                        acknowledged: None,
+                    });
+                }
+                drop(session);
+
+                let mut accept = command(*b"OK");
+                accept.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+                accept.extend_from_slice(&token.to_be_bytes());
+                accept.extend_from_slice(&self.ssrc.to_be_bytes());
+                accept.extend_from_slice(self.name.as_bytes());
+                accept.push(0);
+                Some(accept)
+            }
+            // Clock sync: the peer sends its time (count 0), we add ours (count 1)
+            // and it works out the latency from its reply (count 2).
+            b"CK" if body.get(4) == Some(&0) => {
+                let mut sync = command(*b"CK");
+                sync.extend_from_slice(&self.ssrc.to_be_bytes());
+                sync.extend_from_slice(&[1, 0, 0, 0]);
+                sync.extend_from_slice(body.get(8..16)?);
+                sync.extend_from_slice(&clock(self.start).to_be_bytes());
+                sync.extend_from_slice(&0u64.to_be_bytes());
+                Some(sync)
+            }
+            b"BY" => {
+                let ssrc = read_u32(body, 8)?;
+                let mut session = lock(&self.shared);
+                let index = session.peers.iter().position(|peer| peer.ssrc == ssrc)?;
+                let peer = session.peers.remove(index);
+                session.prune();
+                drop(session);
+                println!("{} left RTP-MIDI session {}", peer.name, self.name);
+                None
+            }
+            // Receiver feedback: everything up to this sequence number arrived
+            b"RS" => {
+                let ssrc = read_u32(body, 0)?;
+                let sequence = u16::from_be_bytes([*body.get(4)?, *body.get(5)?]);
+                let mut session = lock(&self.shared);
+                let latest = session.sent.checked_sub(1)?;
+                // Sequence numbers wrap at 16 bits
+                let behind = latest.wrapping_sub(u64::from(sequence)) & 0xFFFF;
+                let acknowledged = latest.checked_sub(behind)?;
+                let peer = session.peers.iter_mut().find(|peer| peer.ssrc == ssrc)?;
+                peer.acknowledged = peer.acknowledged.max(Some(acknowledged));
+                session.prune();
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The recovery journal's view of the stream: note and controller changes not
+/// every peer has acknowledged, per channel (RFC 6295 chapters N and C).
+#[derive(Debug, Default)]
+struct Journal {
+    /// Packets up to this one are acknowledged; the journal covers the rest.
+    checkpoint: u64,
+    /// (channel, controller) to value and the packet that set it.
+    controllers: BTreeMap<(u8, u8), (u8, u64)>,
+    /// (channel, note) to its velocity, or `None` once ended, and the packet.
+    notes: BTreeMap<(u8, u8), (Option<u8>, u64)>,
+}
+
+impl Journal {
+    fn observe(&mut self, sequence: u64, message: &[u8]) {
+        match *message {
+            [status, note, velocity] if status & 0xF0 == NOTE_ON && velocity > 0 => {
+                self.notes.insert((status & 0x0F, note & 0x7F), (Some(velocity), sequence));
+            }
+            [status, note, _] if status & 0xF0 == NOTE_OFF || status & 0xF0 == NOTE_ON => {
+                self.notes.insert((status & 0x0F, note & 0x7F), (None, sequence));
+            }
+            [status, control, value] if status & 0xF0 == CONTROL_CHANGE => {
+                self.controllers.insert((status & 0x0F, control & 0x7F), (value, sequence));
+            }
+            _ => {}
+        }
+    }
+
+    fn acknowledge(&mut self, sequence: u64) {
+        if sequence < self.checkpoint {
+            return;
+        }
+        self.checkpoint = sequence;
+        self.controllers.retain(|_, &mut (_, changed)| changed > sequence);
+        self.notes.retain(|_, &mut (_, changed)| changed > sequence);
+    }
+
+    /// The journal to append to the next packet, if there is anything to recover.
+    fn encode(&self) -> Option<Vec<u8>> {
+        let mut channels = Vec::new();
+        let mut total: u8 = 0;
+        for channel in 0..16 {
+            let mut chapters = Vec::new();
+            let mut flags = 0;
+
+            // Chapter C: one log per controller, S = 0 and A = 0 (plain value)
+            let controllers: Vec<_> = self.controllers.range((channel, 0)..=(channel, 127)).collect();
+            if !controllers.is_empty() {
+                flags |= 0x40;
+                chapters.push(u8::try_from(controllers.len() - 1).ok()?);
+                for (&(_, control), &(value, _)) in controllers {
+                    chapters.extend_from_slice(&[control, value & 0x7F]);
+                }
+            }
+
+            // Chapter N: logs for sounding notes (Y set: play them on recovery),
+            // offbits for ended ones
+            let notes: Vec<_> = self.notes.range((channel, 0)..=(channel, 127)).collect();
+            if !notes.is_empty() {
+                flags |= 0x08;
+                let on: Vec<_> = notes
+                    .iter()
+                    .filter_map(|(&(_, note), &(velocity, _))| Some((note, velocity?)))
+                    .take(126)
+                    .collect();
+                let off: Vec<u8> = notes
+                    .iter()
+                    .filter(|(_, (velocity, _))| velocity.is_none())
+                    .map(|(&(_, note), _)| note)
+                    .collect();
+                let (low, high) = match (off.first(), off.last()) {
+                    (Some(first), Some(last)) => (first / 8, last / 8),
+                    // LOW > HIGH: no offbits
+                    _ => (1, 0),
+                };
+                chapters.push(u8::try_from(on.len()).ok()?);
+                chapters.push(low << 4 | high);
+                for (note, velocity) in on {
+                    chapters.extend_from_slice(&[note, 0x80 | velocity.max(1)]);
+                }
+                for octet in low..=high {
+                    let bits = off
+                        .iter()
+                        .filter(|&&note| note / 8 == octet)
+                        .fold(0u8, |bits, &note| bits | 0x80 >> (note % 8));
+                    chapters.push(bits);
+                }
+            }
+
+            if flags != 0 {
+                // Header: S = 0, channel, H = 0, 10-bit length including the header
+                let [high, low] = u16::try_from(chapters.len() + 3).ok()?.to_be_bytes();
+                channels.push(channel << 3 | (high & 0x03));
+                channels.push(low);
+                channels.push(flags);
+                channels.extend_from_slice(&chapters);
+                total += 1;
+            }
+        }
+        if total == 0 {
+            return None;
+        }
+
+        let mut journal = Vec::new();
+        // S = 0, Y = 0 (no system journal), A = 1, H = 0, TOTCHAN = channels - 1
+        journal.push(0x20 | (total - 1));
+        journal.extend_from_slice(&self.checkpoint.to_be_bytes()[6..]);
+        journal.extend_from_slice(&channels);
+        Some(journal)
+    }
+}
+
+fn command(name: [u8; 2]) -> Vec<u8> {
+    [SIGNATURE, name].concat()
+}
+
+fn lock(shared: &Mutex<Session>) -> MutexGuard<'_, Session> {
+    shared.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Session time in the 10 kHz units of RTP timestamps and clock sync.
+fn clock(start: Instant) -> u64 {
+    u64::try_from(start.elapsed().as_micros() / 100).unwrap_or(u64::MAX)
+}
+
+fn read_u32(body: &[u8], offset: usize) -> Option<u32> {
+    let bytes = body.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes(bytes.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER_SSRC: u32 = 0x1234_5678;
+    const OTHER_SSRC: u32 = 0x9ABC_DEF0;
+    const TOKEN: u32 = 0xCAFE_F00D;
+
+    /// A session on the first free pair of ports from an ephemeral one up.
+    fn open_session() -> (RtpMidiSession, u16) {
+        let base = UdpSocket::bind("127.0.0.1:0")
+            .and_then(|socket| socket.local_addr())
+            .map(|address| address.port())
+            .expect("an ephemeral port");
+        (base..base.saturating_add(100))
+            .find_map(|port| Some((RtpMidiSession::open("test", port).ok()?, port)))
+            .expect("two free ports")
+    }
+
+    fn peer_socket() -> UdpSocket {
+        let socket = UdpSocket::bind("127.0.0.1:0").expect("a peer socket");
+        socket.set_read_timeout(Some(Duration::from_secs(2))).expect("a read timeout");
+        socket
+    }
+
+    fn exchange(socket: &UdpSocket, port: u16, packet: &[u8]) -> Vec<u8> {
+        socket.send_to(packet, ("127.0.0.1", port)).expect("peer sends");
+        receive(socket)
+    }
+
+    fn receive(socket: &UdpSocket) -> Vec<u8> {
+        let mut buf = [0; 1500];
+        let (len, _) = socket.recv_from(&mut buf).expect("session answers");
+        buf[..len].to_vec()
+    }
+
+    fn invitation(ssrc: u32) -> Vec<u8> {
+        let mut invitation = command(*b"IN");
+        invitation.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+        invitation.extend_from_slice(&TOKEN.to_be_bytes());
+        invitation.extend_from_slice(&ssrc.to_be_bytes());
+        invitation.extend_from_slice(b"peer\0");
+        invitation
+    }
+
+    fn accept(session: &RtpMidiSession) -> Vec<u8> {
+        let mut accept = command(*b"OK");
+        accept.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+        accept.extend_from_slice(&TOKEN.to_be_bytes());
+        accept.extend_from_slice(&session.ssrc.to_be_bytes());
+        accept.extend_from_slice(b"test\0");
+        accept
+    }
+
+    /// Invite `session` on both ports as `ssrc`; returns the control and data sockets.
+    fn join(session: &RtpMidiSession, port: u16, ssrc: u32) -> (UdpSocket, UdpSocket) {
+        let (control, data) = (peer_socket(), peer_socket());
+        assert_eq!(exchange(&control, port, &invitation(ssrc)), accept(session));
+        assert_eq!(exchange(&data, port + 1, &invitation(ssrc)), accept(session));
+        (control, data)
+    }
+
+    fn clock_sync(count: u8, timestamps: [u64; 3]) -> Vec<u8> {
+        let mut sync = command(*b"CK");
+        sync.extend_from_slice(&PEER_SSRC.to_be_bytes());
+        sync.extend_from_slice(&[count, 0, 0, 0]);
+        for timestamp in timestamps {
+            sync.extend_from_slice(&timestamp.to_be_bytes());
+        }
+        sync
+    }
+
+    /// Report everything up to `sequence` received, then wait for the session to
+    /// have handled that: the clock sync sent after it is answered on the same thread.
+    fn acknowledge(data: &UdpSocket, port: u16, ssrc: u32, sequence: u16) {
+        let mut feedback = command(*b"RS");
+        feedback.extend_from_slice(&ssrc.to_be_bytes());
+        feedback.extend_from_slice(&sequence.to_be_bytes());
+        feedback.extend_from_slice(&[0, 0]);
+        data.send_to(&feedback, ("127.0.0.1", port + 1)).expect("peer sends");
+        exchange(data, port + 1, &clock_sync(0, [0; 3]));
+    }
+
+    #[test]
+    fn loopback_peer_joins_and_receives_notes_with_journal() {
+        let (mut session, port) = open_session();
+        let (control, data) = (peer_socket(), peer_socket());
+
+        // Invitation on the control port, then on the data port
+        assert_eq!(exchange(&control, port, &invitation(PEER_SSRC)), accept(&session));
+        assert!(!session.is_online());
+        assert_eq!(exchange(&data, port + 1, &invitation(PEER_SSRC)), accept(&session));
+        assert!(session.is_online());
+        assert!(session.poll());
+
+        // Clock sync: our CK0 comes back as CK1 with its timestamp and the session's
+        let sync = exchange(&data, port + 1, &clock_sync(0, [1000, 0, 0]));
+        assert_eq!(sync[..12], [&command(*b"CK")[..], &session.ssrc.to_be_bytes(), &[1, 0, 0, 0]].concat());
+        assert_eq!(sync[12..20], 1000u64.to_be_bytes());
+        assert_eq!(sync.len(), 36);
+        let ours = u64::from_be_bytes(sync[20..28].try_into().expect("eight bytes"));
+        data.send_to(&clock_sync(2, [1000, ours, 1002]), ("127.0.0.1", port + 1)).expect("peer sends");
+
+        session.send(Duration::ZERO, &[0x90, 60, 100]).expect("note on is sent");
+        let packet = receive(&data);
+        assert_eq!(packet[..4], [0x80, PAYLOAD_TYPE, 0, 0]);
+        assert_eq!(packet[8..12], session.ssrc.to_be_bytes());
+        // Short header, no journal yet: nothing came before
+        assert_eq!(packet[12..], [0x03, 0x90, 60, 100]);
+
+        session.send(Duration::ZERO, &[0x80, 60, 0]).expect("note off is sent");
+        let packet = receive(&data);
+        assert_eq!(packet[..4], [0x80, PAYLOAD_TYPE, 0, 1]);
+        // J flag set, then the note off
+        assert_eq!(packet[12..16], [0x43, 0x80, 60, 0]);
+        // Journal: A = 1 with one channel, checkpoint 0; channel 0, length 7,
+        // chapter N with note 60 sounding at velocity 100 (Y set) and no offbits
+        assert_eq!(packet[16..], [0x20, 0, 0, 0x00, 7, 0x08, 1, 0x10, 60, 0xE4]);
+    }
+
+    #[test]
+    fn journal_keeps_what_any_peer_has_not_acknowledged() {
+        let (mut session, port) = open_session();
+        let (_first_control, first) = join(&session, port, PEER_SSRC);
+        let (_second_control, second) = join(&session, port, OTHER_SSRC);
+        let mut send = |message: &[u8]| {
+            session.send(Duration::ZERO, message).expect("message is sent");
+            let packet = receive(&first);
+            assert_eq!(receive(&second), packet);
+            packet
+        };
+        send(&[0x90, 60, 100]);
+        send(&[0x80, 60, 0]);
+
+        // Only the first peer has reported both: the note off stays in the journal
+        // as an offbit for note 60, with the checkpoint still at 0
+        acknowledge(&first, port, PEER_SSRC, 1);
+        let packet = send(&[0xB0, 7, 99]);
+        assert_eq!(packet[12..], [0x43, 0xB0, 7, 99, 0x20, 0, 0, 0x00, 6, 0x08, 0, 0x77, 0x08]);
+
+        // Now both have the note off, the second peer the controller too: only that is left
+        acknowledge(&second, port, OTHER_SSRC, 2);
+        let packet = send(&[0x90, 62, 1]);
+        assert_eq!(packet[12..], [0x43, 0x90, 62, 1, 0x20, 0, 1, 0x00, 6, 0x40, 0, 7, 99]);
+    }
+}
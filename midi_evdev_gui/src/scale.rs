@@ -0,0 +1,275 @@
+use serde::{de, Deserialize, Deserializer};
+use std::fmt;
+
+const NOTE_NAMES: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+
+/// Scales by name, as semitones above the root.
+const SCALES: &[(&str, &[u8])] = &[
+    ("major", &[0, 2, 4, 5, 7, 9, 11]),
+    ("minor", &[0, 2, 3, 5, 7, 8, 10]),
+    ("ionian", &[0, 2, 4, 5, 7, 9, 11]),
+    ("dorian", &[0, 2, 3, 5, 7, 9, 10]),
+    ("phrygian", &[0, 1, 3, 5, 7, 8, 10]),
+    ("lydian", &[0, 2, 4, 6, 7, 9, 11]),
+    ("mixolydian", &[0, 2, 4, 5, 7, 9, 10]),
+    ("aeolian", &[0, 2, 3, 5, 7, 8, 10]),
+    ("locrian", &[0, 1, 3, 5, 6, 8, 10]),
+    ("harmonic_minor", &[0, 2, 3, 5, 7, 8, 11]),
+    ("melodic_minor", &[0, 2, 3, 5, 7, 9, 11]),
+    ("major_pentatonic", &[0, 2, 4, 7, 9]),
+    ("minor_pentatonic", &[0, 3, 5, 7, 10]),
+    ("blues", &[0, 3, 5, 6, 7, 10]),
+    ("chromatic", &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11]),
+];
+
+/// A MIDI note, by number or by name: `"C4"` is middle C (60), `"F#2"`, `"Bb3"`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pitch(pub u8);
+
+impl TryFrom<&str> for Pitch {
+    type Error = UnknownNote;
+
+    fn try_from(name: &str) -> Result<Self, Self::Error> {
+        let unknown = || UnknownNote(name.to_string());
+        let mut chars = name.trim().chars();
+        let letter = chars.next().ok_or_else(unknown)?.to_ascii_uppercase();
+        let mut semitone = match letter {
+            'C' => 0,
+            'D' => 2,
+            'E' => 4,
+            'F' => 5,
+            'G' => 7,
+            'A' => 9,
+            'B' => 11,
+            _ => return Err(unknown()),
+        };
+        let rest = chars.as_str();
+        let octave = match rest.chars().next() {
+            Some('#') => {
+                semitone += 1;
+                &rest[1..]
+            }
+            Some('b') => {
+                semitone -= 1;
+                &rest[1..]
+            }
+            _ => rest,
+        };
+        let octave: i32 = if octave.is_empty() { 4 } else { octave.parse().map_err(|_| unknown())? };
+        u8::try_from((octave + 1) * 12 + semitone).ok().filter(|&note| note < 128).map(Self).ok_or_else(unknown)
+    }
+}
+
+impl fmt::Display for Pitch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let octave = i32::from(self.0 / 12) - 1;
+        write!(f, "{}{octave}", NOTE_NAMES[usize::from(self.0 % 12)])
+    }
+}
+
+impl<'de> Deserialize<'de> for Pitch {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Number(u8),
+            Name(String),
+        }
+
+        match Raw::deserialize(deserializer)? {
+            Raw::Number(note) if note < 128 => Ok(Self(note)),
+            Raw::Number(note) => Err(de::Error::custom(UnknownNote(note.to_string()))),
+            Raw::Name(name) => Self::try_from(name.as_str()).map_err(de::Error::custom),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownNote(String);
+
+impl fmt::Display for UnknownNote {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown note '{}', expected 0-127 or a name like C4 or F#2", self.0)
+    }
+}
+
+impl std::error::Error for UnknownNote {}
+
+/// A scale by name (`"dorian"`, `"minor_pentatonic"`, ...) or as a list of
+/// semitones above the root, starting at 0: `[0, 2, 3, 7, 9]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Scale {
+    pub name: String,
+    intervals: Vec<u8>,
+}
+
+impl Default for Scale {
+    fn default() -> Self {
+        Self {
+            name: "major".to_string(),
+            intervals: SCALES[0].1.to_vec(),
+        }
+    }
+}
+
+impl Scale {
+    /// The note `degree` steps up the scale from `root`, into the octaves above;
+    /// negative degrees count down. `None` past the MIDI note range.
+    pub fn note(&self, root: i32, degree: i32) -> Option<u8> {
+        let len = i32::try_from(self.intervals.len()).ok()?;
+        let octave = degree.div_euclid(len);
+        let interval = self.intervals[usize::try_from(degree.rem_euclid(len)).ok()?];
+        u8::try_from(root + octave * 12 + i32::from(interval)).ok().filter(|&note| note < 128)
+    }
+}
+
+impl<'de> Deserialize<'de> for Scale {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Name(String),
+            Intervals(Vec<u8>),
+        }
+
+        match Raw::deserialize(deserializer)? {
+            Raw::Name(name) => match SCALES.iter().find(|(known, _)| *known == name) {
+                Some((_, intervals)) => Ok(Self {
+                    name,
+                    intervals: intervals.to_vec(),
+                }),
+                None => Err(de::Error::custom(InvalidScale(format!("unknown scale {name}")))),
+            },
+            Raw::Intervals(intervals) => {
+                let ascending = intervals.windows(2).all(|pair| pair[0] < pair[1]);
+                if intervals.first() != Some(&0) || !ascending || intervals.last().is_some_and(|&last| last > 11) {
+                    let reason = format!("scale {intervals:?} must rise from 0 to at most 11 semitones");
+                    return Err(de::Error::custom(InvalidScale(reason)));
+                }
+                Ok(Self {
+                    name: "custom".to_string(),
+                    intervals,
+                })
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidScale(String);
+
+impl fmt::Display for InvalidScale {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for InvalidScale {}
+
+/// The key that `degree` mappings play in, transposed at runtime.
+#[derive(Debug)]
+pub struct Key {
+    root: Pitch,
+    scale: Scale,
+    /// Semitones the root has been moved by since the profile was loaded.
+    transpose: i32,
+}
+
+impl Key {
+    pub const fn new(root: Pitch, scale: Scale) -> Self {
+        Self {
+            root,
+            scale,
+            transpose: 0,
+        }
+    }
+
+    /// The root as it is now.
+    pub fn root(&self) -> Pitch {
+        Pitch(u8::try_from(i32::from(self.root.0) + self.transpose).unwrap_or(self.root.0))
+    }
+
+    pub fn note(&self, degree: i32) -> Option<u8> {
+        self.scale.note(i32::from(self.root().0), degree)
+    }
+
+    /// Move the root by `semitones`, as far as the MIDI note range allows.
+    pub fn transpose(&mut self, semitones: i32) {
+        let root = i32::from(self.root.0);
+        self.transpose = (root + self.transpose + semitones).clamp(0, 127) - root;
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.root(), self.scale.name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct Config {
+        scale: Scale,
+    }
+
+    fn scale(text: &str) -> Result<Scale, toml::de::Error> {
+        toml::from_str::<Config>(&format!("scale = {text}")).map(|config| config.scale)
+    }
+
+    #[test]
+    fn pitch_names_cover_the_note_range() {
+        assert_eq!(Pitch::try_from("C4").map(|pitch| pitch.0).ok(), Some(60));
+        assert_eq!(Pitch::try_from("Bb3").map(|pitch| pitch.0).ok(), Some(58));
+        assert_eq!(Pitch::try_from("C-1").map(|pitch| pitch.0).ok(), Some(0));
+        assert_eq!(Pitch::try_from("C").map(|pitch| pitch.0).ok(), Some(60));
+        assert_eq!(Pitch::try_from("G9").map(|pitch| pitch.0).ok(), Some(127));
+        for name in ["Cb-1", "G#9", "H4", "C#x", ""] {
+            assert!(Pitch::try_from(name).is_err(), "{name}");
+        }
+        assert_eq!(Pitch(61).to_string(), "C#4");
+    }
+
+    #[test]
+    fn degrees_count_both_ways_from_the_root() {
+        let major = Scale::default();
+        assert_eq!(major.note(60, 0), Some(60));
+        assert_eq!(major.note(60, 2), Some(64));
+        assert_eq!(major.note(60, 7), Some(72));
+        assert_eq!(major.note(60, -1), Some(59));
+        assert_eq!(major.note(60, -7), Some(48));
+    }
+
+    #[test]
+    fn degrees_past_the_note_range_have_no_note() {
+        let major = Scale::default();
+        assert_eq!(major.note(120, 4), Some(127));
+        assert_eq!(major.note(120, 5), None);
+        assert_eq!(major.note(0, -1), None);
+    }
+
+    #[test]
+    fn transpose_stops_at_the_note_range() {
+        let mut key = Key::new(Pitch(60), Scale::default());
+        key.transpose(100);
+        assert_eq!(key.root(), Pitch(127));
+        key.transpose(-1);
+        assert_eq!(key.root(), Pitch(126));
+        key.transpose(-200);
+        assert_eq!(key.root(), Pitch(0));
+        assert_eq!(key.note(1), Some(2));
+        key.transpose(2);
+        assert_eq!(key.to_string(), "D-1 major");
+    }
+
+    #[test]
+    fn custom_intervals_must_rise_from_0_within_an_octave() {
+        assert_eq!(scale("[0, 3, 7]").map(|scale| scale.name).ok().as_deref(), Some("custom"));
+        assert_eq!(scale("[0, 3, 7]").ok().and_then(|scale| scale.note(60, 4)), Some(75));
+        for text in ["[2, 4, 7]", "[0, 4, 4, 7]", "[0, 7, 4]", "[0, 4, 12]", "[]", "\"mixolydianish\""] {
+            assert!(scale(text).is_err(), "{text}");
+        }
+    }
+}
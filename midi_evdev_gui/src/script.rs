@@ -0,0 +1,278 @@
+use crate::macros::check_message;
+use color_eyre::eyre::{eyre, Result};
+use midi_types::status::{
+    CHANNEL_PRESSURE, CONTROL_CHANGE, NOTE_OFF, NOTE_ON, PITCH_BEND_CHANGE, PROGRAM_CHANGE,
+};
+use rhai::module_resolvers::DummyModuleResolver;
+use rhai::{Array, CallFnOptions, Dynamic, Engine, Map, Scope, AST};
+use rosc::{OscMessage, OscType};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Operations one call may run before it is stopped, so a runaway loop cannot
+/// hang the engine.
+const MAX_OPERATIONS: u64 = 100_000;
+
+/// What a script asks to send.
+#[derive(Debug)]
+pub enum ScriptOutput {
+    Midi(Vec<u8>),
+    /// To the `osc_outputs` entry with this name, or the first.
+    Osc(Option<String>, OscMessage),
+}
+
+/// Rhai scripts that mappings hand their input to.
+///
+/// Scripts run sandboxed: no file, module or `eval` access, and a budget of
+/// operations, call depth and sizes. Each script keeps a state map across calls,
+/// and reloads of its file, as `this`.
+pub struct Scripts {
+    engine: Engine,
+    scripts: Vec<Script>,
+}
+
+struct Script {
+    path: PathBuf,
+    ast: AST,
+    state: Dynamic,
+    modified: Option<SystemTime>,
+}
+
+impl Scripts {
+    pub fn new() -> Self {
+        let mut engine = Engine::new();
+        engine
+            .set_module_resolver(DummyModuleResolver::new())
+            .set_max_operations(MAX_OPERATIONS)
+            .set_max_call_levels(32)
+            .set_max_string_size(64 * 1024)
+            .set_max_array_size(10_000)
+            .set_max_map_size(10_000)
+            .disable_symbol("eval")
+            .on_print(|text| println!("[script] {text}"))
+            .on_debug(|text, _, position| println!("[script {position}] {text}"));
+
+        // Shorthands for the maps scripts return
+        engine
+            .register_fn("cc", |control: i64, value: i64| message(&[("cc", control), ("value", value)]))
+            .register_fn("note", |note: i64, velocity: i64| message(&[("note", note), ("velocity", velocity)]))
+            .register_fn("pitch_bend", |value: i64| message(&[("pitch_bend", value)]))
+            .register_fn("pressure", |value: i64| message(&[("pressure", value)]))
+            .register_fn("program", |program: i64| message(&[("program", program)]))
+            .register_fn("midi", |bytes: Array| Map::from([("midi".into(), Dynamic::from(bytes))]))
+            .register_fn("osc", |address: &str, args: Array| {
+                Map::from([("osc".into(), Dynamic::from(address.to_string())), ("args".into(), Dynamic::from(args))])
+            });
+        Self {
+            engine,
+            scripts: Vec::new(),
+        }
+    }
+
+    /// Load `path`, or find it already loaded, and check it has `function` taking
+    /// one argument. Returns the script's index.
+    pub fn load(&mut self, path: &Path, function: &str) -> Result<usize> {
+        let index = if let Some(index) = self.scripts.iter().position(|script| script.path == path) {
+            index
+        } else {
+            let ast = self.compile(path)?;
+            self.scripts.push(Script {
+                path: path.to_path_buf(),
+                ast,
+                state: Dynamic::from(Map::new()),
+                modified: modified(path),
+            });
+            self.scripts.len() - 1
+        };
+        let script = &self.scripts[index];
+        if !script.ast.iter_functions().any(|f| f.name == function && f.params.len() == 1) {
+            return Err(eyre!("Script {} has no function {function}(input)", path.display()));
+        }
+        Ok(index)
+    }
+
+    /// Carry over the state of the scripts `previous` also ran, for a reload.
+    pub fn keep_state(&mut self, previous: &Self) {
+        for script in &mut self.scripts {
+            if let Some(old) = previous.scripts.iter().find(|old| old.path == script.path) {
+                script.state = old.state.clone();
+            }
+        }
+    }
+
+    /// Recompile the scripts whose files changed. One that no longer compiles
+    /// keeps running its last good version. State carries over either way.
+    pub fn reload_changed(&mut self) {
+        for index in 0..self.scripts.len() {
+            let path = self.scripts[index].path.clone();
+            let modified = modified(&path);
+            if modified == self.scripts[index].modified {
+                continue;
+            }
+            self.scripts[index].modified = modified;
+            match self.compile(&path) {
+                Ok(ast) => {
+                    self.scripts[index].ast = ast;
+                    println!("Reloaded script {}", path.display());
+                }
+                Err(e) => eprintln!("{e}"),
+            }
+        }
+    }
+
+    /// Call `function` of script `index` with `input`, turning what it returns
+    /// (a message map, an array of them or nothing) into messages. MIDI messages
+    /// go on `channel` unless they name one.
+    pub fn call(&mut self, index: usize, function: &str, input: Map, channel: u8) -> Result<Vec<ScriptOutput>> {
+        let script = &mut self.scripts[index];
+        let options = CallFnOptions::new().eval_ast(false).bind_this_ptr(&mut script.state);
+        let result: Dynamic = self
+            .engine
+            .call_fn_with_options(options, &mut Scope::new(), &script.ast, function, (input,))
+            .map_err(|e| eyre!("Script {} failed in {function}: {e}", script.path.display()))?;
+
+        let messages = if result.is_unit() {
+            Vec::new()
+        } else if result.is_array() {
+            result.cast::<Array>()
+        } else {
+            vec![result]
+        };
+        let path = script.path.display();
+        messages
+            .into_iter()
+            .map(|message| {
+                let map = message.try_cast::<Map>().ok_or_else(|| eyre!("Script {path} returned a non-message"))?;
+                output(&map, channel).ok_or_else(|| eyre!("Script {path} returned a malformed message {map:?}"))
+            })
+            .collect()
+    }
+
+    fn compile(&self, path: &Path) -> Result<AST> {
+        self.engine
+            .compile_file(path.to_path_buf())
+            .map_err(|e| eyre!("Failed to compile script {}: {e}", path.display()))
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+fn message(fields: &[(&str, i64)]) -> Map {
+    fields.iter().map(|&(key, value)| (key.into(), Dynamic::from(value))).collect()
+}
+
+/// A number field, ints as they are and floats rounded, saturating.
+#[allow(clippy::cast_possible_truncation)]
+fn number(map: &Map, key: &str) -> Option<i64> {
+    let value = map.get(key)?;
+    value.as_int().ok().or_else(|| value.as_float().ok().map(|float| float.round() as i64))
+}
+
+fn data(value: i64) -> u8 {
+    u8::try_from(value.clamp(0, 127)).unwrap_or_default()
+}
+
+/// The message a returned map describes.
+fn output(map: &Map, channel: u8) -> Option<ScriptOutput> {
+    let channel = number(map, "channel").map_or(channel, |channel| data(channel.clamp(0, 15)));
+    let bytes = if let Some(control) = number(map, "cc") {
+        vec![CONTROL_CHANGE | channel, data(control), data(number(map, "value")?)]
+    } else if let Some(note) = number(map, "note") {
+        match number(map, "velocity").unwrap_or(127) {
+            velocity if velocity > 0 => vec![NOTE_ON | channel, data(note), data(velocity)],
+            _ => vec![NOTE_OFF | channel, data(note), 0],
+        }
+    } else if let Some(bend) = number(map, "pitch_bend") {
+        let value = bend.clamp(-8192, 8191) + 8192;
+        vec![PITCH_BEND_CHANGE | channel, data(value & 0x7F), data(value >> 7)]
+    } else if let Some(pressure) = number(map, "pressure") {
+        vec![CHANNEL_PRESSURE | channel, data(pressure)]
+    } else if let Some(program) = number(map, "program") {
+        vec![PROGRAM_CHANGE | channel, data(program)]
+    } else if let Some(bytes) = map.get("midi") {
+        let bytes = bytes.clone().try_cast::<Array>()?;
+        let bytes: Vec<u8> = bytes
+            .iter()
+            .map(|byte| byte.as_int().ok().and_then(|byte| u8::try_from(byte).ok()))
+            .collect::<Option<_>>()?;
+        check_message(&bytes).ok()?;
+        bytes
+    } else if let Some(address) = map.get("osc") {
+        let args = map.get("args").and_then(|args| args.clone().try_cast::<Array>()).unwrap_or_default();
+        let message = OscMessage {
+            addr: address.clone().into_string().ok()?,
+            args: args.into_iter().map(osc_arg).collect::<Option<_>>()?,
+        };
+        let output = map.get("output").and_then(|output| output.clone().into_string().ok());
+        return Some(ScriptOutput::Osc(output, message));
+    } else {
+        return None;
+    };
+    Some(ScriptOutput::Midi(bytes))
+}
+
+/// A script value as an OSC argument; floats are narrowed to OSC's 32 bits.
+#[allow(clippy::cast_possible_truncation)]
+fn osc_arg(value: Dynamic) -> Option<OscType> {
+    if let Ok(int) = value.as_int() {
+        return i32::try_from(int).ok().map(OscType::Int);
+    }
+    if let Ok(float) = value.as_float() {
+        return Some(OscType::Float(float as f32));
+    }
+    if let Ok(flag) = value.as_bool() {
+        return Some(OscType::Bool(flag));
+    }
+    value.into_string().ok().map(OscType::String)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn midi(map: &Map, channel: u8) -> Option<Vec<u8>> {
+        match output(map, channel)? {
+            ScriptOutput::Midi(bytes) => Some(bytes),
+            ScriptOutput::Osc(..) => None,
+        }
+    }
+
+    fn raw(bytes: &[i64]) -> Map {
+        let bytes: Array = bytes.iter().copied().map(Dynamic::from).collect();
+        Map::from([("midi".into(), Dynamic::from(bytes))])
+    }
+
+    #[test]
+    fn messages_become_midi_on_the_mappings_channel() {
+        assert_eq!(midi(&message(&[("cc", 7), ("value", 100)]), 2), Some(vec![0xB2, 7, 100]));
+        assert_eq!(midi(&message(&[("cc", 7), ("value", 300)]), 2), Some(vec![0xB2, 7, 127]));
+        assert_eq!(midi(&message(&[("note", 60), ("velocity", 90)]), 0), Some(vec![0x90, 60, 90]));
+        assert_eq!(midi(&message(&[("note", 60)]), 0), Some(vec![0x90, 60, 127]));
+        assert_eq!(midi(&message(&[("note", 60), ("velocity", 0)]), 0), Some(vec![0x80, 60, 0]));
+        assert_eq!(midi(&message(&[("pitch_bend", 0)]), 0), Some(vec![0xE0, 0x00, 0x40]));
+        assert_eq!(midi(&message(&[("pitch_bend", -9000)]), 0), Some(vec![0xE0, 0x00, 0x00]));
+        assert_eq!(midi(&message(&[("pitch_bend", 9000)]), 0), Some(vec![0xE0, 0x7F, 0x7F]));
+        assert!(midi(&message(&[("cc", 7)]), 0).is_none());
+        assert!(midi(&message(&[("tempo", 120)]), 0).is_none());
+    }
+
+    #[test]
+    fn message_channel_overrides_and_is_clamped() {
+        assert_eq!(midi(&message(&[("program", 5), ("channel", 9)]), 2), Some(vec![0xC9, 5]));
+        assert_eq!(midi(&message(&[("pressure", 64), ("channel", 20)]), 2), Some(vec![0xDF, 64]));
+        assert_eq!(midi(&message(&[("pressure", 64), ("channel", -1)]), 2), Some(vec![0xD0, 64]));
+    }
+
+    #[test]
+    fn raw_midi_must_be_a_status_byte_and_data() {
+        assert_eq!(midi(&raw(&[0x90, 60, 1]), 0), Some(vec![0x90, 60, 1]));
+        assert_eq!(midi(&raw(&[0xF0, 0x7D, 0x01, 0xF7]), 0), Some(vec![0xF0, 0x7D, 0x01, 0xF7]));
+        assert!(midi(&raw(&[60]), 0).is_none());
+        assert!(midi(&raw(&[0x90, 200, 1]), 0).is_none());
+        assert!(midi(&raw(&[0x90, 256, 1]), 0).is_none());
+        assert!(midi(&raw(&[]), 0).is_none());
+    }
+}
@@ -1,18 +1,18 @@
 extern crate sdl2;
 
+use color_eyre::eyre::{eyre, Result};
 use sdl2::event::Event;
 use sdl2::joystick::Joystick;
-use sdl2::EventPump;
 use std::time::{SystemTime, UNIX_EPOCH};
 
-fn main() {
-    let sdl_context = sdl2::init().unwrap();
-    let joystick_subsystem = sdl_context.joystick().unwrap();
+fn main() -> Result<()> {
+    let sdl_context = sdl2::init().map_err(|e| eyre!(e))?;
+    let joystick_subsystem = sdl_context.joystick().map_err(|e| eyre!(e))?;
 
-    let joysticks = open_virpil_joysticks(&joystick_subsystem);
+    let joysticks = open_virpil_joysticks(&joystick_subsystem)?;
 
     // Store joystick names for easy access
-    let joystick_names: Vec<String> = joysticks.iter().map(|j| j.name()).collect();
+    let joystick_names: Vec<String> = joysticks.iter().map(Joystick::name).collect();
 
     // Print information about each opened joystick
     for joystick in &joysticks {
@@ -22,26 +22,26 @@ fn main() {
         println!("Number of balls: {}", joystick.num_balls());
     }
 
-    let mut event_pump = sdl_context.event_pump().unwrap();
+    let mut event_pump = sdl_context.event_pump().map_err(|e| eyre!(e))?;
     loop {
         for event in event_pump.poll_iter() {
             let timestamp = current_millis();
             match event {
                 Event::JoyAxisMotion { which, axis_idx, value, .. } => {
                     let name = &joystick_names[which as usize];
-                    println!("{}: Joystick '{}', Axis {} moved to {}", timestamp, name, axis_idx, value);
+                    println!("{timestamp}: Joystick '{name}', Axis {axis_idx} moved to {value}");
                 },
                 Event::JoyButtonDown { which, button_idx, .. } => {
                     let name = &joystick_names[which as usize];
-                    println!("{}: Joystick '{}', Button {} down", timestamp, name, button_idx);
+                    println!("{timestamp}: Joystick '{name}', Button {button_idx} down");
                 },
                 Event::JoyButtonUp { which, button_idx, .. } => {
                     let name = &joystick_names[which as usize];
-                    println!("{}: Joystick '{}', Button {} up", timestamp, name, button_idx);
+                    println!("{timestamp}: Joystick '{name}', Button {button_idx} up");
                 },
                 Event::JoyHatMotion { which, hat_idx, state, .. } => {
                     let name = &joystick_names[which as usize];
-                    println!("{}: Joystick '{}', Hat {} moved to {:?}", timestamp, name, hat_idx, state);
+                    println!("{timestamp}: Joystick '{name}', Hat {hat_idx} moved to {state:?}");
                 },
                 _ => {}
             }
@@ -49,9 +49,14 @@ fn main() {
     }
 }
 
-pub fn open_virpil_joysticks(joystick_subsystem: &sdl2::JoystickSubsystem) -> Vec<Joystick> {
+/// Open every VIRPIL joystick SDL can see.
+///
+/// # Errors
+///
+/// If SDL cannot count the joysticks.
+pub fn open_virpil_joysticks(joystick_subsystem: &sdl2::JoystickSubsystem) -> Result<Vec<Joystick>> {
     let mut joysticks = Vec::new();
-    for id in 0..joystick_subsystem.num_joysticks().unwrap() {
+    for id in 0..joystick_subsystem.num_joysticks().map_err(|e| eyre!(e))? {
         if let Ok(name) = joystick_subsystem.name_for_index(id) {
             if name.contains("VIRPIL") {
                 if let Ok(joystick) = joystick_subsystem.open(id) {
@@ -60,9 +65,9 @@ pub fn open_virpil_joysticks(joystick_subsystem: &sdl2::JoystickSubsystem) -> Ve
             }
         }
     }
-    joysticks
+    Ok(joysticks)
 }
 
 fn current_millis() -> u128 {
-    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis()
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_millis())
 }
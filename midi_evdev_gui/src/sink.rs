@@ -0,0 +1,138 @@
+use crate::ump::{self, Voice};
+use color_eyre::eyre::Result;
+#[cfg(test)]
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+/// Somewhere the engine writes MIDI to.
+///
+/// Messages are complete (never a partial SysEx) and stamped with the time of
+/// the input event that produced them.
+pub trait MidiSink {
+    /// Name used in log messages.
+    fn name(&self) -> &str;
+
+    fn send(&mut self, timestamp: Duration, message: &[u8]) -> Result<()>;
+
+    /// Send a MIDI 2.0 message. Sinks that only take bytes get its MIDI 1.0
+    /// form, or nothing for per-note messages.
+    fn send_midi2(&mut self, timestamp: Duration, message: Voice) -> Result<()> {
+        message.to_midi1().map_or(Ok(()), |bytes| self.send(timestamp, &bytes))
+    }
+
+    /// Whether messages currently reach their destination.
+    fn is_online(&self) -> bool {
+        true
+    }
+
+    /// Called periodically to check on the destination and reconnect to it.
+    /// Returns true when the sink has just come back online.
+    fn poll(&mut self) -> bool {
+        false
+    }
+}
+
+/// A message captured by a [`MemorySink`].
+#[cfg(test)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Recorded {
+    pub timestamp: Duration,
+    pub bytes: Vec<u8>,
+}
+
+/// Keeps everything sent to it, so the conversion logic can be checked without
+/// an ALSA sequencer. Clones share the same recording.
+#[cfg(test)]
+#[derive(Debug, Clone, Default)]
+pub struct MemorySink {
+    name: String,
+    messages: Arc<Mutex<Vec<Recorded>>>,
+}
+
+#[cfg(test)]
+impl MemorySink {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            messages: Arc::default(),
+        }
+    }
+
+    pub fn messages(&self) -> Vec<Recorded> {
+        self.messages.lock().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    pub fn clear(&self) {
+        self.messages.lock().unwrap_or_else(PoisonError::into_inner).clear();
+    }
+}
+
+#[cfg(test)]
+impl MidiSink for MemorySink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn send(&mut self, timestamp: Duration, message: &[u8]) -> Result<()> {
+        self.messages
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(Recorded {
+                timestamp,
+                bytes: message.to_vec(),
+            });
+        Ok(())
+    }
+}
+
+/// Prints every message as hex on stdout instead of sending it anywhere.
+pub struct HexDumpSink {
+    name: String,
+    /// Print Universal MIDI Packet words instead of MIDI 1.0 bytes.
+    ump: bool,
+}
+
+impl HexDumpSink {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            ump: false,
+        }
+    }
+
+    pub fn ump(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            ump: true,
+        }
+    }
+
+    fn print(&self, timestamp: Duration, hex: &[String]) {
+        println!("{:>10}.{:06} {}: {}", timestamp.as_secs(), timestamp.subsec_micros(), self.name, hex.join(" "));
+    }
+}
+
+impl MidiSink for HexDumpSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn send(&mut self, timestamp: Duration, message: &[u8]) -> Result<()> {
+        let hex: Vec<_> = if self.ump {
+            ump::packets(message).iter().map(|word| format!("{word:08X}")).collect()
+        } else {
+            message.iter().map(|byte| format!("{byte:02X}")).collect()
+        };
+        self.print(timestamp, &hex);
+        Ok(())
+    }
+
+    fn send_midi2(&mut self, timestamp: Duration, message: Voice) -> Result<()> {
+        if !self.ump {
+            return message.to_midi1().map_or(Ok(()), |bytes| self.send(timestamp, &bytes));
+        }
+        let hex: Vec<_> = message.words().iter().map(|word| format!("{word:08X}")).collect();
+        self.print(timestamp, &hex);
+        Ok(())
+    }
+}
@@ -0,0 +1,178 @@
+use color_eyre::eyre::{Result, WrapErr};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Ticks per quarter note.
+const DIVISION: u16 = 960;
+/// 120 BPM, so a tick is 520.83µs.
+const MICROS_PER_QUARTER: u32 = 500_000;
+
+/// Which track a message goes on: output index and channel, `None` for SysEx.
+type TrackKey = (usize, Option<u8>);
+
+/// Collects outgoing messages and writes them as a Type 1 Standard MIDI File,
+/// one track per output and channel, after a tempo track.
+#[derive(Debug)]
+pub struct Recorder {
+    path: PathBuf,
+    start: Duration,
+    tracks: BTreeMap<TrackKey, Vec<(u64, Vec<u8>)>>,
+}
+
+impl Recorder {
+    /// Start recording to `path`; `start` is time zero of the file.
+    pub fn new(path: PathBuf, start: Duration) -> Self {
+        println!("Recording to {}", path.display());
+        Self {
+            path,
+            start,
+            tracks: BTreeMap::new(),
+        }
+    }
+
+    /// Note a message sent to `output` at `timestamp`. Real-time and system
+    /// common messages have no place in a file and are left out.
+    pub fn record(&mut self, output: usize, timestamp: Duration, message: &[u8]) {
+        let channel = match message.first() {
+            Some(&status) if (0x80..0xF0).contains(&status) => Some(status & 0x0F),
+            Some(0xF0) => None,
+            _ => return,
+        };
+        let micros = timestamp.saturating_sub(self.start).as_micros();
+        let ticks = micros * u128::from(DIVISION) / u128::from(MICROS_PER_QUARTER);
+        self.tracks
+            .entry((output, channel))
+            .or_default()
+            .push((u64::try_from(ticks).unwrap_or(u64::MAX), message.to_vec()));
+    }
+
+    /// Write the file, naming each track after its output.
+    pub fn finish(self, output_names: &[String]) -> Result<()> {
+        let mut file = Vec::new();
+        file.extend_from_slice(b"MThd");
+        file.extend_from_slice(&6u32.to_be_bytes());
+        file.extend_from_slice(&1u16.to_be_bytes());
+        let tracks = u16::try_from(self.tracks.len() + 1).unwrap_or(u16::MAX);
+        file.extend_from_slice(&tracks.to_be_bytes());
+        file.extend_from_slice(&DIVISION.to_be_bytes());
+
+        let mut tempo = Vec::new();
+        tempo.extend_from_slice(&[0x00, 0xFF, 0x51, 0x03]);
+        tempo.extend_from_slice(&MICROS_PER_QUARTER.to_be_bytes()[1..]);
+        tempo.extend_from_slice(&[0x00, 0xFF, 0x58, 0x04, 0x04, 0x02, 0x18, 0x08]);
+        write_track(&mut file, tempo);
+
+        for ((output, channel), mut events) in self.tracks {
+            let output_name = output_names.get(output).map_or("output", String::as_str);
+            let name = channel.map_or_else(
+                || format!("{output_name} SysEx"),
+                |channel| format!("{output_name} ch {}", channel + 1),
+            );
+            let mut track = Vec::new();
+            write_vlq(&mut track, 0);
+            track.extend_from_slice(&[0xFF, 0x03]);
+            write_vlq(&mut track, name.len() as u64);
+            track.extend_from_slice(name.as_bytes());
+
+            // Thru messages are stamped on arrival, so order can be slightly off
+            events.sort_by_key(|&(ticks, _)| ticks);
+            let mut last = 0;
+            for (ticks, message) in events {
+                write_vlq(&mut track, ticks - last);
+                last = ticks;
+                if let [0xF0, data @ ..] = message.as_slice() {
+                    track.push(0xF0);
+                    write_vlq(&mut track, data.len() as u64);
+                    track.extend_from_slice(data);
+                } else {
+                    track.extend_from_slice(&message);
+                }
+            }
+            write_track(&mut file, track);
+        }
+
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir).wrap_err_with(|| format!("Failed to create {}", dir.display()))?;
+        }
+        fs::write(&self.path, file).wrap_err_with(|| format!("Failed to write {}", self.path.display()))?;
+        println!("Saved recording to {}", self.path.display());
+        Ok(())
+    }
+}
+
+/// Append an `MTrk` chunk, ending the track.
+fn write_track(file: &mut Vec<u8>, mut events: Vec<u8>) {
+    events.extend_from_slice(&[0x00, 0xFF, 0x2F, 0x00]);
+    file.extend_from_slice(b"MTrk");
+    file.extend_from_slice(&u32::try_from(events.len()).unwrap_or(u32::MAX).to_be_bytes());
+    file.extend_from_slice(&events);
+}
+
+/// Variable-length quantity: 7 bits per byte, most significant first.
+fn write_vlq(out: &mut Vec<u8>, value: u64) {
+    // SMF quantities are at most four bytes
+    let value = value.min(0x0FFF_FFFF);
+    let mut shift = 21;
+    while shift > 0 && value >> shift == 0 {
+        shift -= 7;
+    }
+    while shift > 0 {
+        out.push(0x80 | ((value >> shift) & 0x7F) as u8);
+        shift -= 7;
+    }
+    out.push((value & 0x7F) as u8);
+}
+
+/// `path`, or the first of `name-1.ext`, `name-2.ext`, ... that does not exist yet.
+pub fn unused_path(path: &Path) -> PathBuf {
+    if !path.exists() {
+        return path.to_path_buf();
+    }
+    let stem = path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+    let extension = path.extension().map(|extension| extension.to_string_lossy().into_owned());
+    (1..u32::MAX)
+        .map(|n| {
+            let name = extension
+                .as_ref()
+                .map_or_else(|| format!("{stem}-{n}"), |extension| format!("{stem}-{n}.{extension}"));
+            path.with_file_name(name)
+        })
+        .find(|candidate| !candidate.exists())
+        .unwrap_or_else(|| path.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vlq(value: u64) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_vlq(&mut out, value);
+        out
+    }
+
+    #[test]
+    fn vlq_uses_as_few_bytes_as_it_can() {
+        assert_eq!(vlq(0), [0x00]);
+        assert_eq!(vlq(0x7F), [0x7F]);
+        assert_eq!(vlq(0x80), [0x81, 0x00]);
+        assert_eq!(vlq(0x2000), [0xC0, 0x00]);
+        assert_eq!(vlq(0x0FFF_FFFF), [0xFF, 0xFF, 0xFF, 0x7F]);
+    }
+
+    #[test]
+    fn vlq_saturates_at_four_bytes() {
+        assert_eq!(vlq(0x1000_0000), [0xFF, 0xFF, 0xFF, 0x7F]);
+    }
+
+    #[test]
+    fn track_chunk_counts_its_end_of_track() {
+        let mut file = Vec::new();
+        write_track(&mut file, vec![0x00, 0x90, 60, 100]);
+        assert_eq!(file[..4], *b"MTrk");
+        assert_eq!(file[4..8], 8u32.to_be_bytes());
+        assert_eq!(file[8..], [0x00, 0x90, 60, 100, 0x00, 0xFF, 0x2F, 0x00]);
+    }
+}
@@ -0,0 +1,130 @@
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+
+const SYSEX_START: u8 = 0xF0;
+const SYSEX_END: u8 = 0xF7;
+
+/// A SysEx message with the mapped 0-127 value written into it.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SysexTemplate {
+    /// The whole message, `F0` to `F7`.
+    pub bytes: Vec<u8>,
+    /// Offsets of the bytes the value replaces.
+    #[serde(default)]
+    pub value_at: Vec<usize>,
+    /// Compute a Roland checksum over the bytes from this offset (the address,
+    /// usually 5) up to the checksum itself, which goes just before the `F7`.
+    pub checksum_from: Option<usize>,
+}
+
+impl SysexTemplate {
+    pub fn validate(&self) -> Result<()> {
+        let len = self.bytes.len();
+        if len < 3 || self.bytes[0] != SYSEX_START || self.bytes[len - 1] != SYSEX_END {
+            return Err(eyre!("SysEx template must start with F0 and end with F7"));
+        }
+        if let Some(byte) = self.bytes[1..len - 1].iter().find(|&&byte| byte > 0x7F) {
+            return Err(eyre!("SysEx template has status byte {byte:02X} inside the message"));
+        }
+        if let Some(offset) = self.value_at.iter().find(|&&offset| offset == 0 || offset >= len - 1) {
+            return Err(eyre!("SysEx value offset {offset} is outside the message body"));
+        }
+        if let Some(from) = self.checksum_from {
+            let checksum_at = len - 2;
+            if from == 0 || from >= checksum_at {
+                return Err(eyre!("SysEx checksum range starting at {from} is outside the message body"));
+            }
+            if self.value_at.contains(&checksum_at) {
+                return Err(eyre!("SysEx value offset {checksum_at} is where the checksum goes"));
+            }
+        }
+        Ok(())
+    }
+
+    /// The message for `value`. Assumes the template passed `validate`.
+    pub fn render(&self, value: u8) -> Vec<u8> {
+        let mut bytes = self.bytes.clone();
+        for &offset in &self.value_at {
+            bytes[offset] = value & 0x7F;
+        }
+        if let Some(from) = self.checksum_from {
+            let checksum_at = bytes.len() - 2;
+            bytes[checksum_at] = roland_checksum(&bytes[from..checksum_at]);
+        }
+        bytes
+    }
+}
+
+/// The value that brings the sum of `data` and itself to a multiple of 128.
+pub fn roland_checksum(data: &[u8]) -> u8 {
+    let sum: u32 = data.iter().map(|&byte| u32::from(byte)).sum();
+    ((128 - sum % 128) % 128) as u8
+}
+
+/// Read the SysEx messages of a `.syx` dump, in file order.
+pub fn load_syx(path: &Path) -> Result<Vec<Vec<u8>>> {
+    let data = fs::read(path).wrap_err_with(|| format!("Failed to read SysEx file {}", path.display()))?;
+    let messages: Vec<Vec<u8>> = data.split_inclusive(|&byte| byte == SYSEX_END).map(<[u8]>::to_vec).collect();
+    if messages.is_empty() {
+        return Err(eyre!("SysEx file {} is empty", path.display()));
+    }
+    for message in &messages {
+        if message.first() != Some(&SYSEX_START) || message.last() != Some(&SYSEX_END) {
+            return Err(eyre!("SysEx file {} is not a sequence of F0 ... F7 messages", path.display()));
+        }
+    }
+    Ok(messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Roland GS master volume (DT1 to address 40 00 04), value at offset 8.
+    const MASTER_VOLUME: &str = "bytes = [0xF0, 0x41, 0x10, 0x42, 0x12, 0x40, 0x00, 0x04, 0x00, 0x00, 0xF7]
+                                 value_at = [8]
+                                 checksum_from = 5";
+
+    fn template(text: &str) -> SysexTemplate {
+        toml::from_str(text).expect("test template parses")
+    }
+
+    #[test]
+    fn roland_checksum_of_gs_reset() {
+        // F0 41 10 42 12 40 00 7F 00 41 F7
+        assert_eq!(roland_checksum(&[0x40, 0x00, 0x7F, 0x00]), 0x41);
+        assert_eq!(roland_checksum(&[]), 0);
+        assert_eq!(roland_checksum(&[0x7F, 0x01]), 0);
+    }
+
+    #[test]
+    fn render_fills_in_value_and_checksum() {
+        let template = template(MASTER_VOLUME);
+        assert!(template.validate().is_ok());
+        assert_eq!(template.render(0x7F), [0xF0, 0x41, 0x10, 0x42, 0x12, 0x40, 0x00, 0x04, 0x7F, 0x3D, 0xF7]);
+        assert_eq!(template.render(0), [0xF0, 0x41, 0x10, 0x42, 0x12, 0x40, 0x00, 0x04, 0x00, 0x3C, 0xF7]);
+    }
+
+    #[test]
+    fn template_without_checksum_only_sets_values() {
+        let template = template("bytes = [0xF0, 0x7D, 0x00, 0x00, 0xF7]\nvalue_at = [2, 3]");
+        assert!(template.validate().is_ok());
+        assert_eq!(template.render(0x45), [0xF0, 0x7D, 0x45, 0x45, 0xF7]);
+    }
+
+    #[test]
+    fn validate_rejects_malformed_templates() {
+        for text in [
+            "bytes = [0xF0, 0x7D, 0x00]",
+            "bytes = [0x7D, 0x00, 0xF7]",
+            "bytes = [0xF0, 0x90, 0x00, 0xF7]",
+            "bytes = [0xF0, 0x7D, 0x00, 0xF7]\nvalue_at = [3]",
+            "bytes = [0xF0, 0x7D, 0x00, 0xF7]\nchecksum_from = 2",
+            "bytes = [0xF0, 0x7D, 0x00, 0x00, 0xF7]\nvalue_at = [3]\nchecksum_from = 1",
+        ] {
+            assert!(template(text).validate().is_err(), "{text}");
+        }
+    }
+}
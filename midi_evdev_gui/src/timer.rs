@@ -0,0 +1,44 @@
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+/// Things to do at given times, soonest first. Timers due at the same time run
+/// in the order they were scheduled.
+#[derive(Debug)]
+pub struct TimerQueue<T> {
+    queue: BTreeMap<(Instant, u64), T>,
+    next_id: u64,
+}
+
+impl<T> Default for TimerQueue<T> {
+    fn default() -> Self {
+        Self {
+            queue: BTreeMap::new(),
+            next_id: 0,
+        }
+    }
+}
+
+impl<T> TimerQueue<T> {
+    pub fn schedule(&mut self, at: Instant, timer: T) {
+        self.queue.insert((at, self.next_id), timer);
+        self.next_id += 1;
+    }
+
+    /// When the soonest timer is due.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.queue.keys().next().map(|&(at, _)| at)
+    }
+
+    /// Remove and return the soonest timer if it is due by `now`, with the time
+    /// it was due at.
+    pub fn pop_due(&mut self, now: Instant) -> Option<(Instant, T)> {
+        let entry = self.queue.first_entry().filter(|entry| entry.key().0 <= now)?;
+        let (at, _) = *entry.key();
+        Some((at, entry.remove()))
+    }
+
+    /// Cancel the timers that do not match.
+    pub fn retain(&mut self, mut keep: impl FnMut(&T) -> bool) {
+        self.queue.retain(|_, timer| keep(timer));
+    }
+}
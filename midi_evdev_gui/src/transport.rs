@@ -0,0 +1,232 @@
+use serde::Deserialize;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// MIDI clock ticks per quarter note.
+const PPQN: f64 = 24.0;
+pub const MIN_TEMPO: f64 = 20.0;
+pub const MAX_TEMPO: f64 = 300.0;
+/// Taps further apart than this start a new count.
+const TAP_TIMEOUT: Duration = Duration::from_secs(2);
+/// How many of the latest taps the tempo is averaged over.
+const TAPS: usize = 4;
+
+pub const CLOCK: u8 = 0xF8;
+
+/// MIDI Machine Control commands, sent to every device (ID 7F).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MmcCommand {
+    Stop,
+    Play,
+    DeferredPlay,
+    FastForward,
+    Rewind,
+    /// Record strobe: punch in.
+    #[serde(rename = "record")]
+    RecordStrobe,
+    /// Punch out.
+    RecordExit,
+    Pause,
+    /// Go to a SMPTE position, `"hh:mm:ss:ff"` at 30 frames per second.
+    Locate(Timecode),
+}
+
+impl MmcCommand {
+    pub fn message(self) -> Vec<u8> {
+        let command = match self {
+            Self::Stop => 0x01,
+            Self::Play => 0x02,
+            Self::DeferredPlay => 0x03,
+            Self::FastForward => 0x04,
+            Self::Rewind => 0x05,
+            Self::RecordStrobe => 0x06,
+            Self::RecordExit => 0x07,
+            Self::Pause => 0x09,
+            Self::Locate(Timecode { hours, minutes, seconds, frames }) => {
+                // Frame rate bits 11: 30 fps non-drop
+                let hours = 0x60 | hours;
+                return vec![0xF0, 0x7F, 0x7F, 0x06, 0x44, 0x06, 0x01, hours, minutes, seconds, frames, 0x00, 0xF7];
+            }
+        };
+        vec![0xF0, 0x7F, 0x7F, 0x06, command, 0xF7]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Timecode {
+    pub hours: u8,
+    pub minutes: u8,
+    pub seconds: u8,
+    pub frames: u8,
+}
+
+impl TryFrom<String> for Timecode {
+    type Error = InvalidTimecode;
+
+    fn try_from(text: String) -> Result<Self, Self::Error> {
+        let Ok(fields) = text.split(':').map(str::parse).collect::<Result<Vec<u8>, _>>() else {
+            return Err(InvalidTimecode(text));
+        };
+        match *fields.as_slice() {
+            [hours, minutes, seconds, frames] if hours < 24 && minutes < 60 && seconds < 60 && frames < 30 => {
+                Ok(Self { hours, minutes, seconds, frames })
+            }
+            _ => Err(InvalidTimecode(text)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidTimecode(String);
+
+impl fmt::Display for InvalidTimecode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid timecode {}, expected hh:mm:ss:ff", self.0)
+    }
+}
+
+impl std::error::Error for InvalidTimecode {}
+
+/// System realtime transport messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Realtime {
+    Start,
+    Continue,
+    Stop,
+}
+
+impl Realtime {
+    pub const fn byte(self) -> u8 {
+        match self {
+            Self::Start => 0xFA,
+            Self::Continue => 0xFB,
+            Self::Stop => 0xFC,
+        }
+    }
+}
+
+/// The internal MIDI clock's tempo, set from the profile, axes and tap tempo.
+#[derive(Debug)]
+pub struct Clock {
+    /// Indices of the outputs that get the ticks.
+    pub outputs: Vec<usize>,
+    tempo: f64,
+    /// Temporary offset from a nudge axis, in BPM.
+    nudge: f64,
+    taps: Vec<Instant>,
+}
+
+impl Clock {
+    pub const fn new(tempo: f64, outputs: Vec<usize>) -> Self {
+        Self {
+            outputs,
+            tempo: tempo.clamp(MIN_TEMPO, MAX_TEMPO),
+            nudge: 0.0,
+            taps: Vec::new(),
+        }
+    }
+
+    /// The tempo the clock runs at, nudge included.
+    pub const fn tempo(&self) -> f64 {
+        (self.tempo + self.nudge).clamp(MIN_TEMPO, MAX_TEMPO)
+    }
+
+    pub const fn set_tempo(&mut self, tempo: f64) {
+        self.tempo = tempo.clamp(MIN_TEMPO, MAX_TEMPO);
+    }
+
+    pub const fn set_nudge(&mut self, nudge: f64) {
+        self.nudge = nudge;
+    }
+
+    /// A tap of the tap tempo button. From the second tap on, the tempo follows
+    /// the average gap between the latest taps.
+    pub fn tap(&mut self, at: Instant) {
+        if self.taps.last().is_some_and(|&last| at.duration_since(last) > TAP_TIMEOUT) {
+            self.taps.clear();
+        }
+        self.taps.push(at);
+        if self.taps.len() > TAPS {
+            self.taps.remove(0);
+        }
+        if let [first, .., last] = self.taps.as_slice() {
+            let gaps = u32::try_from(self.taps.len() - 1).unwrap_or(1);
+            let gap = last.duration_since(*first) / gaps;
+            self.set_tempo(60.0 / gap.as_secs_f64());
+        }
+    }
+
+    /// Time between two ticks.
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs_f64(60.0 / self.tempo() / PPQN)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timecode(text: &str) -> Result<Timecode, InvalidTimecode> {
+        Timecode::try_from(text.to_string())
+    }
+
+    fn assert_tempo(clock: &Clock, tempo: f64) {
+        assert!((clock.tempo() - tempo).abs() < 1e-9, "tempo {} is not {tempo}", clock.tempo());
+    }
+
+    #[test]
+    fn timecode_fields_are_bounded() {
+        let parsed = timecode("23:59:59:29").expect("the last frame of the day parses");
+        assert_eq!((parsed.hours, parsed.minutes, parsed.seconds, parsed.frames), (23, 59, 59, 29));
+        for text in ["24:00:00:00", "00:60:00:00", "00:00:60:00", "00:00:00:30", "00:00:00", "1:2:3:x", ""] {
+            assert!(timecode(text).is_err(), "{text}");
+        }
+    }
+
+    #[test]
+    fn mmc_messages() {
+        assert_eq!(MmcCommand::Play.message(), [0xF0, 0x7F, 0x7F, 0x06, 0x02, 0xF7]);
+        assert_eq!(MmcCommand::RecordStrobe.message(), [0xF0, 0x7F, 0x7F, 0x06, 0x06, 0xF7]);
+        assert_eq!(MmcCommand::Pause.message(), [0xF0, 0x7F, 0x7F, 0x06, 0x09, 0xF7]);
+        // The hours byte carries the frame rate, 30 fps, in bits 5-6
+        let locate = MmcCommand::Locate(timecode("01:02:03:04").expect("timecode parses"));
+        assert_eq!(locate.message(), [0xF0, 0x7F, 0x7F, 0x06, 0x44, 0x06, 0x01, 0x61, 2, 3, 4, 0x00, 0xF7]);
+    }
+
+    #[test]
+    fn tap_tempo_averages_the_latest_taps() {
+        let start = Instant::now();
+        let mut clock = Clock::new(100.0, Vec::new());
+        clock.tap(start);
+        assert_tempo(&clock, 100.0);
+        for tap in 1..=2 {
+            clock.tap(start + Duration::from_millis(500) * tap);
+        }
+        assert_tempo(&clock, 120.0);
+
+        // A long pause starts counting again
+        let restart = start + Duration::from_secs(4);
+        clock.tap(restart);
+        assert_tempo(&clock, 120.0);
+        clock.tap(restart + Duration::from_millis(250));
+        assert_tempo(&clock, 240.0);
+    }
+
+    #[test]
+    fn nudge_offsets_the_tick_interval() {
+        let mut clock = Clock::new(120.0, Vec::new());
+        // 24 ticks per quarter note: 60 s / 120 / 24
+        assert_eq!(clock.interval().as_micros(), 20_833);
+        clock.set_nudge(30.0);
+        assert_tempo(&clock, 150.0);
+        assert_eq!(clock.interval().as_micros(), 16_666);
+        clock.set_nudge(-500.0);
+        assert_tempo(&clock, MIN_TEMPO);
+        clock.set_tempo(1000.0);
+        clock.set_nudge(0.0);
+        assert_tempo(&clock, MAX_TEMPO);
+    }
+}
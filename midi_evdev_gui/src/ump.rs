@@ -0,0 +1,259 @@
+use crate::sink::MidiSink;
+use color_eyre::eyre::{Result, WrapErr};
+use midi_types::status::{
+    CHANNEL_PRESSURE, CONTROL_CHANGE, KEY_PRESSURE, NOTE_OFF, NOTE_ON, PITCH_BEND_CHANGE,
+};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+
+/// Every packet goes out on UMP group 1.
+const GROUP: u32 = 0;
+
+/// A MIDI 2.0 channel voice message, at the resolution the mapping produced it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Voice {
+    NoteOn { channel: u8, note: u8, velocity: u16 },
+    NoteOff { channel: u8, note: u8, velocity: u16 },
+    ControlChange { channel: u8, control: u8, value: u32 },
+    PitchBend { channel: u8, value: u32 },
+    ChannelPressure { channel: u8, value: u32 },
+    PolyPressure { channel: u8, note: u8, value: u32 },
+    /// Bends one note only; centre is `0x8000_0000`.
+    PerNotePitchBend { channel: u8, note: u8, value: u32 },
+    /// A registered per-note controller (1 is modulation, 74 brightness, ...).
+    PerNoteController { channel: u8, note: u8, controller: u8, value: u32 },
+}
+
+impl Voice {
+    /// The 64-bit MIDI 2.0 channel voice packet (message type 4).
+    pub fn words(self) -> [u32; 2] {
+        // Status, channel, the two index bytes and the 32 data bits
+        let (status, channel, index, data) = match self {
+            Self::NoteOn { channel, note, velocity } => (0x9, channel, [note, 0], u32::from(velocity) << 16),
+            Self::NoteOff { channel, note, velocity } => (0x8, channel, [note, 0], u32::from(velocity) << 16),
+            Self::ControlChange { channel, control, value } => (0xB, channel, [control, 0], value),
+            Self::PitchBend { channel, value } => (0xE, channel, [0, 0], value),
+            Self::ChannelPressure { channel, value } => (0xD, channel, [0, 0], value),
+            Self::PolyPressure { channel, note, value } => (0xA, channel, [note, 0], value),
+            Self::PerNotePitchBend { channel, note, value } => (0x6, channel, [note, 0], value),
+            Self::PerNoteController { channel, note, controller, value } => (0x0, channel, [note, controller], value),
+        };
+        let index = u32::from(u16::from_be_bytes(index));
+        let first = 0x4 << 28 | GROUP << 24 | status << 20 | u32::from(channel & 0x0F) << 16 | index;
+        [first, data]
+    }
+
+    /// The nearest MIDI 1.0 message, for ports that only speak bytes. Per-note
+    /// messages have none.
+    pub fn to_midi1(self) -> Option<Vec<u8>> {
+        let bytes = match self {
+            Self::NoteOn { channel, note, velocity } => {
+                // Velocity 0 means note off in MIDI 1.0, so never round down to it
+                vec![NOTE_ON | channel, note, ((velocity >> 9) as u8).max(1)]
+            }
+            Self::NoteOff { channel, note, velocity } => vec![NOTE_OFF | channel, note, (velocity >> 9) as u8],
+            Self::ControlChange { channel, control, value } => {
+                vec![CONTROL_CHANGE | channel, control, (value >> 25) as u8]
+            }
+            Self::PitchBend { channel, value } => {
+                let value = value >> 18;
+                vec![PITCH_BEND_CHANGE | channel, (value & 0x7F) as u8, (value >> 7) as u8]
+            }
+            Self::ChannelPressure { channel, value } => vec![CHANNEL_PRESSURE | channel, (value >> 25) as u8],
+            Self::PolyPressure { channel, note, value } => vec![KEY_PRESSURE | channel, note, (value >> 25) as u8],
+            Self::PerNotePitchBend { .. } | Self::PerNoteController { .. } => return None,
+        };
+        Some(bytes)
+    }
+
+    /// The MIDI 2.0 form of a MIDI 1.0 channel voice message, values scaled up
+    /// as the UMP specification describes.
+    pub fn from_midi1(message: &[u8]) -> Option<Self> {
+        let [status, data @ ..] = message else {
+            return None;
+        };
+        let channel = status & 0x0F;
+        let voice = match (status & 0xF0, data) {
+            (NOTE_ON, &[note, 0]) => Self::NoteOff { channel, note, velocity: 0 },
+            (NOTE_ON, &[note, velocity]) => {
+                let velocity = u16::try_from(scale_up(velocity.into(), 7, 16)).unwrap_or(u16::MAX);
+                Self::NoteOn { channel, note, velocity }
+            }
+            (NOTE_OFF, &[note, velocity]) => {
+                let velocity = u16::try_from(scale_up(velocity.into(), 7, 16)).unwrap_or(u16::MAX);
+                Self::NoteOff { channel, note, velocity }
+            }
+            (CONTROL_CHANGE, &[control, value]) => {
+                Self::ControlChange { channel, control, value: scale_up(value.into(), 7, 32) }
+            }
+            (PITCH_BEND_CHANGE, &[lsb, msb]) => {
+                let value = u32::from(msb) << 7 | u32::from(lsb);
+                Self::PitchBend { channel, value: scale_up(value, 14, 32) }
+            }
+            (CHANNEL_PRESSURE, &[value]) => Self::ChannelPressure { channel, value: scale_up(value.into(), 7, 32) },
+            (KEY_PRESSURE, &[note, value]) => {
+                Self::PolyPressure { channel, note, value: scale_up(value.into(), 7, 32) }
+            }
+            _ => return None,
+        };
+        Some(voice)
+    }
+}
+
+/// Widen `value` from `from` to `to` bits so that minimum, centre and maximum
+/// stay minimum, centre and maximum (min-center-max scaling).
+pub fn scale_up(value: u32, from: u32, to: u32) -> u32 {
+    let shift = to - from;
+    let shifted = u64::from(value) << shift;
+    if value <= 1 << (from - 1) {
+        return u32::try_from(shifted).unwrap_or(u32::MAX);
+    }
+    // Above the centre, fill the new low bits by repeating the value's own bits
+    let repeat_bits = from - 1;
+    let mut repeat = u64::from(value) & ((1 << repeat_bits) - 1);
+    repeat = if shift > repeat_bits { repeat << (shift - repeat_bits) } else { repeat >> (repeat_bits - shift) };
+    let mut result = shifted;
+    while repeat != 0 {
+        result |= repeat;
+        repeat >>= repeat_bits;
+    }
+    u32::try_from(result).unwrap_or(u32::MAX)
+}
+
+/// UMP packets for any MIDI 1.0 message: MIDI 2.0 channel voice where there is
+/// an equivalent, otherwise MIDI 1.0 channel voice, system or 7-bit SysEx packets.
+pub fn packets(message: &[u8]) -> Vec<u32> {
+    if let Some(voice) = Voice::from_midi1(message) {
+        return voice.words().to_vec();
+    }
+    let byte = |index: usize| u32::from(message.get(index).copied().unwrap_or_default());
+    match message.first() {
+        Some(0xF0) => {
+            let payload = message.get(1..message.len().saturating_sub(1)).unwrap_or_default();
+            let chunks: Vec<_> = payload.chunks(6).collect();
+            let mut words = Vec::new();
+            for (index, chunk) in chunks.iter().enumerate() {
+                let status = match (index, chunks.len()) {
+                    (_, 1) => 0x0,
+                    (0, _) => 0x1,
+                    (index, count) if index + 1 == count => 0x3,
+                    _ => 0x2,
+                };
+                let mut data = [0u8; 6];
+                data[..chunk.len()].copy_from_slice(chunk);
+                words.push(
+                    0x3 << 28
+                        | GROUP << 24
+                        | status << 20
+                        | u32::try_from(chunk.len()).unwrap_or_default() << 16
+                        | u32::from(data[0]) << 8
+                        | u32::from(data[1]),
+                );
+                words.push(u32::from_be_bytes([data[2], data[3], data[4], data[5]]));
+            }
+            if words.is_empty() {
+                words.extend_from_slice(&[0x3 << 28 | GROUP << 24, 0]);
+            }
+            words
+        }
+        Some(&status) if status >= 0xF1 => vec![0x1 << 28 | GROUP << 24 | byte(0) << 16 | byte(1) << 8 | byte(2)],
+        Some(_) => vec![0x2 << 28 | GROUP << 24 | byte(0) << 16 | byte(1) << 8 | byte(2)],
+        None => Vec::new(),
+    }
+}
+
+/// Writes Universal MIDI Packets to an ALSA UMP device (`/dev/snd/umpC1D0`).
+pub struct UmpOutput {
+    name: String,
+    device: File,
+}
+
+impl UmpOutput {
+    pub fn open(name: &str, path: &Path) -> Result<Self> {
+        let device = OpenOptions::new()
+            .write(true)
+            .open(path)
+            .wrap_err_with(|| format!("Failed to open UMP device {}", path.display()))?;
+        println!("Sending MIDI 2.0 to {}", path.display());
+        Ok(Self {
+            name: name.to_string(),
+            device,
+        })
+    }
+
+    fn write(&mut self, words: &[u32]) -> Result<()> {
+        let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_ne_bytes()).collect();
+        self.device.write_all(&bytes).wrap_err_with(|| format!("Failed to write to {}", self.name))
+    }
+}
+
+impl MidiSink for UmpOutput {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn send(&mut self, _timestamp: Duration, message: &[u8]) -> Result<()> {
+        self.write(&packets(message))
+    }
+
+    fn send_midi2(&mut self, _timestamp: Duration, message: Voice) -> Result<()> {
+        self.write(&message.words())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scale_up_keeps_minimum_centre_and_maximum() {
+        assert_eq!([0, 64, 127].map(|value| scale_up(value, 7, 16)), [0, 0x8000, 0xFFFF]);
+        assert_eq!([0, 64, 127].map(|value| scale_up(value, 7, 32)), [0, 0x8000_0000, 0xFFFF_FFFF]);
+        assert_eq!([0, 0x2000, 0x3FFF].map(|value| scale_up(value, 14, 32)), [0, 0x8000_0000, 0xFFFF_FFFF]);
+    }
+
+    #[test]
+    fn scale_up_repeats_bits_above_the_centre() {
+        // 0x50 is 0b101_0000: the six bits below the top one fill the new bits
+        assert_eq!(scale_up(0x50, 7, 16), 0xA082);
+        assert_eq!(scale_up(63, 7, 16), 63 << 9);
+    }
+
+    #[test]
+    fn voice_packet_layout() {
+        let note = Voice::NoteOn {
+            channel: 2,
+            note: 60,
+            velocity: 0xFFFF,
+        };
+        assert_eq!(note.words(), [0x4092_3C00, 0xFFFF_0000]);
+        let controller = Voice::PerNoteController {
+            channel: 15,
+            note: 60,
+            controller: 74,
+            value: 5,
+        };
+        assert_eq!(controller.words(), [0x400F_3C4A, 5]);
+    }
+
+    #[test]
+    fn midi1_messages_become_packets() {
+        assert_eq!(packets(&[0xB0, 7, 127]), [0x40B0_0700, 0xFFFF_FFFF]);
+        assert_eq!(packets(&[0xF8]), [0x10F8_0000]);
+        assert_eq!(packets(&[0xC3, 5]), [0x20C3_0500]);
+        assert_eq!(packets(&[0xF0, 0x7D, 0x01, 0xF7]), [0x3002_7D01, 0]);
+        // Seven data bytes: a start packet of six and an end packet of one
+        let sysex = [0xF0, 1, 2, 3, 4, 5, 6, 7, 0xF7];
+        assert_eq!(packets(&sysex), [0x3016_0102, 0x0304_0506, 0x3031_0700, 0]);
+    }
+
+    #[test]
+    fn midi1_round_trip() {
+        for message in [[0x91, 60, 100], [0x81, 60, 64], [0xB4, 74, 33], [0xE0, 0x00, 0x40], [0xA2, 61, 1]] {
+            let voice = Voice::from_midi1(&message).expect("a channel voice message");
+            assert_eq!(voice.to_midi1().as_deref(), Some(&message[..]));
+        }
+    }
+}
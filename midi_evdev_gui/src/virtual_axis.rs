@@ -0,0 +1,131 @@
+use serde::Deserialize;
+use std::f64::consts::TAU;
+
+/// Below this distance from centre the stick has no meaningful angle.
+const ANGLE_DEADZONE: f64 = 0.05;
+
+/// How a virtual axis is computed from the normalised (0.0-1.0) values of
+/// its device's axes and keys. The result is normalised too.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Combine {
+    /// Sum of the components, up to 1.0.
+    Sum(Vec<String>),
+    /// First minus second, centred: 0.5 when equal.
+    Difference([String; 2]),
+    Average(Vec<String>),
+    Min(Vec<String>),
+    Max(Vec<String>),
+    /// Angle of an `[x, y]` stick around its centre: 0.0 pointing up, a full
+    /// turn clockwise back to 1.0. Held while the stick is centred.
+    Angle([String; 2]),
+    /// Distance of an `[x, y]` stick from its centre, 1.0 at full deflection.
+    Radius([String; 2]),
+    /// From `from` to `to` as `by` goes from 0.0 to 1.0.
+    Crossfade { from: String, to: String, by: String },
+}
+
+impl Combine {
+    pub fn components(&self) -> Vec<&str> {
+        match self {
+            Self::Sum(codes) | Self::Average(codes) | Self::Min(codes) | Self::Max(codes) => {
+                codes.iter().map(String::as_str).collect()
+            }
+            Self::Difference(codes) | Self::Angle(codes) | Self::Radius(codes) => {
+                codes.iter().map(String::as_str).collect()
+            }
+            Self::Crossfade { from, to, by } => vec![from, to, by],
+        }
+    }
+
+    /// The combined value, or `None` while it is undefined: a component the
+    /// device has not reported yet, or a centred stick's angle.
+    pub fn compute(&self, value: impl Fn(&str) -> Option<f64>) -> Option<f64> {
+        let values = self.components().into_iter().map(value).collect::<Option<Vec<_>>>()?;
+        let combined = match (self, values.as_slice()) {
+            (Self::Sum(_), values) => values.iter().sum::<f64>(),
+            (Self::Difference(_), &[a, b]) => f64::midpoint(a - b, 1.0),
+            (Self::Average(_), values) if !values.is_empty() => {
+                values.iter().sum::<f64>() / f64::from(u32::try_from(values.len()).ok()?)
+            }
+            (Self::Min(_), values) => values.iter().copied().reduce(f64::min)?,
+            (Self::Max(_), values) => values.iter().copied().reduce(f64::max)?,
+            (Self::Angle(_), &[x, y]) => {
+                let (dx, dy) = (x - 0.5, y - 0.5);
+                if dx.hypot(dy) < ANGLE_DEADZONE {
+                    return None;
+                }
+                // evdev's Y grows downwards, so up is -dy
+                dx.atan2(-dy).rem_euclid(TAU) / TAU
+            }
+            (Self::Radius(_), &[x, y]) => (x - 0.5).hypot(y - 0.5) * 2.0,
+            (Self::Crossfade { .. }, &[from, to, by]) => (to - from).mul_add(by, from),
+            _ => return None,
+        };
+        Some(combined.clamp(0.0, 1.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn codes<const N: usize>(names: [&str; N]) -> [String; N] {
+        names.map(str::to_string)
+    }
+
+    /// `combine` over axes `A`, `B` and `C` at these values.
+    fn compute(combine: &Combine, values: [f64; 3]) -> Option<f64> {
+        combine.compute(|code| ["A", "B", "C"].iter().position(|&name| name == code).map(|index| values[index]))
+    }
+
+    #[test]
+    fn sum_stops_at_full_scale() {
+        let sum = Combine::Sum(codes(["A", "B"]).to_vec());
+        assert_eq!(compute(&sum, [0.25, 0.5, 0.0]), Some(0.75));
+        assert_eq!(compute(&sum, [0.7, 0.6, 0.0]), Some(1.0));
+    }
+
+    #[test]
+    fn average_min_and_max() {
+        let values = [0.2, 0.4, 0.9];
+        assert_eq!(compute(&Combine::Average(codes(["A", "B", "C"]).to_vec()), values), Some(0.5));
+        assert_eq!(compute(&Combine::Min(codes(["A", "B", "C"]).to_vec()), values), Some(0.2));
+        assert_eq!(compute(&Combine::Max(codes(["A", "B", "C"]).to_vec()), values), Some(0.9));
+    }
+
+    #[test]
+    fn angle_turns_clockwise_from_up() {
+        let angle = Combine::Angle(codes(["A", "B"]));
+        assert_eq!(compute(&angle, [0.5, 0.0, 0.0]), Some(0.0));
+        assert_eq!(compute(&angle, [1.0, 0.5, 0.0]), Some(0.25));
+        assert_eq!(compute(&angle, [0.5, 1.0, 0.0]), Some(0.5));
+        assert_eq!(compute(&angle, [0.0, 0.5, 0.0]), Some(0.75));
+        assert_eq!(compute(&angle, [0.5 + ANGLE_DEADZONE / 2.0, 0.5, 0.0]), None);
+    }
+
+    #[test]
+    fn radius_is_full_at_the_edge() {
+        let radius = Combine::Radius(codes(["A", "B"]));
+        assert_eq!(compute(&radius, [0.5, 0.5, 0.0]), Some(0.0));
+        assert_eq!(compute(&radius, [1.0, 0.5, 0.0]), Some(1.0));
+        assert_eq!(compute(&radius, [0.5, 0.0, 0.0]), Some(1.0));
+        assert_eq!(compute(&radius, [1.0, 1.0, 0.0]), Some(1.0));
+    }
+
+    #[test]
+    fn crossfade_goes_from_one_to_the_other() {
+        let [from, to, by] = codes(["A", "B", "C"]);
+        let crossfade = Combine::Crossfade { from, to, by };
+        assert_eq!(compute(&crossfade, [0.2, 0.8, 0.0]), Some(0.2));
+        assert_eq!(compute(&crossfade, [0.2, 0.8, 1.0]), Some(0.8));
+        assert_eq!(compute(&crossfade, [0.2, 0.8, 0.5]), Some(0.5));
+    }
+
+    #[test]
+    fn missing_component_leaves_it_undefined() {
+        let difference = Combine::Difference(codes(["A", "D"]));
+        assert_eq!(compute(&difference, [0.5, 0.5, 0.5]), None);
+        assert_eq!(compute(&Combine::Difference(codes(["A", "B"])), [0.5, 0.5, 0.0]), Some(0.5));
+    }
+}